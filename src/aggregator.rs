@@ -0,0 +1,203 @@
+use crate::searcher::SourceImage;
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// A source found by one or more searchers, merged by its canonical url.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AggregatedImage {
+    pub url: String,
+    pub searchers: Vec<String>,
+    pub similarity: f64,
+    pub low_confidence: bool,
+    pub metadata: HashMap<String, String>,
+    pub preview: Option<String>,
+}
+
+/// Returns a key identifying the artwork behind `url` and the url to show for it.
+pub fn canonicalize_url(url: &str) -> (String, String) {
+    lazy_static! {
+        static ref TWITTER_REGEX: Regex = Regex::new(
+            r"^https?://(?:(?:www|mobile)\.)?(?:twitter|x)\.com/([^/]+)/status(?:es)?/(\d+)"
+        )
+        .unwrap();
+        static ref DANBOORU_REGEX: Regex =
+            Regex::new(r"^https?://danbooru\.donmai\.us/(?:posts|post/show)/(\d+)").unwrap();
+        // only artworks, as ids of users and novels are in other namespaces
+        static ref PIXIV_REGEX: Regex = Regex::new(
+            r"^https?://(?:www\.)?pixiv\.net/(?:(?:[a-z]{2}/)?artworks/(\d+)|member_illust\.php\?(?:.*&)?illust_id=(\d+))"
+        )
+        .unwrap();
+    }
+
+    if let Some(caps) = PIXIV_REGEX.captures(url) {
+        let id = caps.get(1).or_else(|| caps.get(2)).unwrap().as_str();
+        return (
+            format!("pixiv:{}", id),
+            format!("https://www.pixiv.net/artworks/{}", id),
+        );
+    }
+    if let Some(caps) = TWITTER_REGEX.captures(url) {
+        return (
+            format!("twitter:{}", &caps[2]),
+            format!("https://twitter.com/{}/status/{}", &caps[1], &caps[2]),
+        );
+    }
+    if let Some(caps) = DANBOORU_REGEX.captures(url) {
+        return (
+            format!("danbooru:{}", &caps[1]),
+            format!("https://danbooru.donmai.us/posts/{}", &caps[1]),
+        );
+    }
+
+    let url = url.trim_end_matches('/').replacen("http://", "https://", 1);
+    let key = url
+        .trim_start_matches("https://")
+        .trim_start_matches("www.")
+        .to_string();
+    (key, url)
+}
+
+/// Merges the results of all searchers, ranking sources that more searchers agree on first.
+pub fn aggregate(mut images: Vec<SourceImage>) -> Vec<AggregatedImage> {
+    images.sort_by(|a, b| b.similarity.total_cmp(&a.similarity));
+
+    let mut keys: HashMap<String, usize> = HashMap::new();
+    let mut results: Vec<AggregatedImage> = vec![];
+    for image in images {
+        let (key, url) = canonicalize_url(&image.url);
+        match keys.get(&key) {
+            Some(&index) => {
+                let result = &mut results[index];
+                if !result.searchers.iter().any(|name| name == image.searcher) {
+                    result.searchers.push(image.searcher.to_string());
+                }
+                result.low_confidence &= image.low_confidence;
+                for (key, value) in image.metadata {
+                    result.metadata.entry(key).or_insert(value);
+                }
+                if result.preview.is_none() {
+                    result.preview = image.preview;
+                }
+            }
+            None => {
+                keys.insert(key, results.len());
+                results.push(AggregatedImage {
+                    url,
+                    searchers: vec![image.searcher.to_string()],
+                    similarity: image.similarity,
+                    low_confidence: image.low_confidence,
+                    metadata: image.metadata,
+                    preview: image.preview,
+                });
+            }
+        }
+    }
+
+    results.sort_by(|a, b| {
+        b.searchers
+            .len()
+            .cmp(&a.searchers.len())
+            .then(b.similarity.total_cmp(&a.similarity))
+    });
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aggregator::{aggregate, canonicalize_url};
+    use crate::searcher::SourceImage;
+    use std::collections::HashMap;
+
+    #[test]
+    fn canonicalize_url_test_1() {
+        assert_eq!(
+            canonicalize_url(
+                "https://www.pixiv.net/member_illust.php?mode=medium&illust_id=99118150"
+            )
+            .0,
+            canonicalize_url("https://www.pixiv.net/artworks/99118150").0
+        );
+    }
+
+    #[test]
+    fn canonicalize_pixiv_user_url_test() {
+        assert_eq!(
+            canonicalize_url("https://www.pixiv.net/users/99118150").0,
+            "pixiv.net/users/99118150"
+        );
+        assert_eq!(
+            canonicalize_url("https://www.pixiv.net/member.php?id=99118150").0,
+            "pixiv.net/member.php?id=99118150"
+        );
+        assert_eq!(
+            canonicalize_url("https://www.pixiv.net/en/artworks/99118150").0,
+            "pixiv:99118150"
+        );
+    }
+
+    #[test]
+    fn canonicalize_url_test_2() {
+        assert_eq!(
+            canonicalize_url("https://x.com/foo/status/1576244843281797120"),
+            (
+                "twitter:1576244843281797120".to_string(),
+                "https://twitter.com/foo/status/1576244843281797120".to_string()
+            )
+        );
+        assert_eq!(
+            canonicalize_url("https://mobile.twitter.com/foo/status/1576244843281797120").0,
+            "twitter:1576244843281797120"
+        );
+    }
+
+    #[test]
+    fn canonicalize_url_test_3() {
+        assert_eq!(
+            canonicalize_url("https://danbooru.donmai.us/post/show/123456").0,
+            canonicalize_url("https://danbooru.donmai.us/posts/123456?q=foo").0
+        );
+    }
+
+    #[test]
+    fn canonicalize_url_test_4() {
+        assert_eq!(
+            canonicalize_url("http://www.example.com/foo/").0,
+            canonicalize_url("https://example.com/foo").0
+        );
+    }
+
+    #[test]
+    fn aggregate_test() {
+        let results = aggregate(vec![
+            SourceImage {
+                url: "https://yande.re/post/show/1".to_string(),
+                searcher: "iqdb",
+                similarity: 0.95,
+                metadata: HashMap::from([("iqdb".to_string(), "".to_string())]),
+                ..Default::default()
+            },
+            SourceImage {
+                url: "https://www.pixiv.net/artworks/99118150".to_string(),
+                searcher: "saucenao",
+                similarity: 0.9,
+                metadata: HashMap::from([("saucenao".to_string(), "".to_string())]),
+                ..Default::default()
+            },
+            SourceImage {
+                url: "https://www.pixiv.net/member_illust.php?illust_id=99118150".to_string(),
+                searcher: "ascii2d",
+                similarity: 0.6,
+                metadata: HashMap::from([("ascii2d".to_string(), "".to_string())]),
+                ..Default::default()
+            },
+        ]);
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].url, "https://www.pixiv.net/artworks/99118150");
+        assert_eq!(results[0].searchers, vec!["saucenao", "ascii2d"]);
+        assert_eq!(results[0].similarity, 0.9);
+        assert_eq!(results[0].metadata.len(), 2);
+        assert_eq!(results[1].searchers, vec!["iqdb"]);
+    }
+}
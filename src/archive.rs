@@ -0,0 +1,413 @@
+use crate::database::*;
+use crate::message::*;
+use crate::{cache, cfg, local_search, searcher, utils};
+use anyhow::{anyhow, Result};
+use chrono::{Local, TimeZone};
+use lazy_static::lazy_static;
+use log::{error, info, warn};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+lazy_static! {
+    pub static ref ARCHIVE: Archive = Archive::open(&DATABASE, &cfg::BOT_CONFIG.download_path)
+        .expect("failed to open image archive");
+    static ref IMAGE_REGEX: Regex = Regex::new(r"\[CQ:image,[^]]*]").unwrap();
+    static ref IMAGE_URL_REGEX: Regex = Regex::new(r"url=([^]]+)]").unwrap();
+}
+
+/// An image posted in a group.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ArchiveRecord {
+    pub id: u64,
+    pub group_id: i64,
+    pub user_id: i64,
+    pub message_id: i32,
+    pub time: u64,
+    pub md5: String,
+}
+
+/// A stored image, shared by the records of the same md5.
+#[derive(Debug, Serialize, Deserialize)]
+struct ArchivedFile {
+    path: String,
+    size: u64,
+    /// Id of the record which posted the image first.
+    first_id: u64,
+    /// Last time the image was posted.
+    time: u64,
+}
+
+/// Images of the archived groups, stored at `<root>/<group_id>/<date>/<md5>.<ext>`.
+///
+/// Records are kept in the tree `archive` keyed by their ids, and files in `archive_files`
+/// keyed by their md5s.
+pub struct Archive {
+    db: sled::Db,
+    root: PathBuf,
+    records: sled::Tree,
+    files: sled::Tree,
+}
+
+impl Archive {
+    pub fn open(db: &sled::Db, root: impl AsRef<Path>) -> Result<Self> {
+        Ok(Archive {
+            db: db.clone(),
+            root: root.as_ref().to_path_buf(),
+            records: db.open_tree("archive")?,
+            files: db.open_tree("archive_files")?,
+        })
+    }
+
+    pub fn has_file(&self, md5: &str) -> Result<bool> {
+        Ok(self.files.contains_key(md5)?)
+    }
+
+    /// Records an image, `image` may be `None` when the md5 is already archived.
+    pub fn store(
+        &self,
+        group_id: i64,
+        user_id: i64,
+        message_id: i32,
+        md5: &str,
+        image: Option<&[u8]>,
+        time: u64,
+    ) -> Result<ArchiveRecord> {
+        let id = self.db.generate_id()?;
+        // the image written by this call, kept when another call archives the same md5 first
+        let mut written = None;
+        let file = loop {
+            let current = self.files.get(md5)?;
+            let file = match (&current, image) {
+                (Some(value), _) => ArchivedFile {
+                    time,
+                    ..serde_json::from_slice(value)?
+                },
+                (None, Some(image)) => match written.take() {
+                    Some(file) => file,
+                    None => self.write_image(group_id, md5, image, id, time)?,
+                },
+                (None, None) => return Err(anyhow!("image {} is not archived", md5)),
+            };
+            let value = serde_json::to_vec(&file)?;
+            match self
+                .files
+                .compare_and_swap(md5, current.clone(), Some(value))?
+            {
+                Ok(()) => break file,
+                Err(_) if current.is_none() => written = Some(file),
+                Err(_) => {}
+            }
+        };
+        if let Some(unused) = written.filter(|unused| unused.path != file.path) {
+            let _ = std::fs::remove_file(unused.path);
+        }
+        let record = ArchiveRecord {
+            id,
+            group_id,
+            user_id,
+            message_id,
+            time,
+            md5: md5.to_string(),
+        };
+        self.records
+            .insert(id.to_be_bytes(), serde_json::to_vec(&record)?)?;
+        Ok(record)
+    }
+
+    fn write_image(
+        &self,
+        group_id: i64,
+        md5: &str,
+        image: &[u8],
+        first_id: u64,
+        time: u64,
+    ) -> Result<ArchivedFile> {
+        let extension = image::guess_format(image)
+            .ok()
+            .and_then(|format| format.extensions_str().first())
+            .unwrap_or(&"jpg");
+        let date = Local
+            .timestamp_opt(time as i64, 0)
+            .single()
+            .ok_or_else(|| anyhow!("invalid timestamp {}", time))?
+            .format("%Y-%m-%d")
+            .to_string();
+        let dir = self.root.join(group_id.to_string()).join(date);
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join(format!("{}.{}", md5, extension));
+        std::fs::write(&path, image)?;
+        Ok(ArchivedFile {
+            path: path.to_string_lossy().to_string(),
+            size: image.len() as u64,
+            first_id,
+            time,
+        })
+    }
+
+    /// Returns the record and the path of the stored image.
+    pub fn get(&self, id: u64) -> Result<Option<(ArchiveRecord, String)>> {
+        let record: ArchiveRecord = match self.records.get(id.to_be_bytes())? {
+            Some(value) => serde_json::from_slice(&value)?,
+            None => return Ok(None),
+        };
+        Ok(self.get_file(&record.md5)?.map(|file| (record, file.path)))
+    }
+
+    /// Returns the first record of a stored image.
+    pub fn first_record(&self, md5: &str) -> Result<Option<ArchiveRecord>> {
+        match self.get_file(md5)? {
+            Some(file) => Ok(self.get(file.first_id)?.map(|(record, _)| record)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_file(&self, md5: &str) -> Result<Option<ArchivedFile>> {
+        match self.files.get(md5)? {
+            Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Removes the images last posted before `now - retention_secs`, and then the least
+    /// recently posted ones until the total size is within `max_total_bytes`.
+    pub fn sweep(
+        &self,
+        now: u64,
+        retention_secs: Option<u64>,
+        max_total_bytes: Option<u64>,
+    ) -> Result<usize> {
+        let mut files = self
+            .files
+            .iter()
+            .map(|item| {
+                let (md5, value) = item?;
+                let file: ArchivedFile = serde_json::from_slice(&value)?;
+                Ok((String::from_utf8(md5.to_vec())?, file))
+            })
+            .collect::<Result<Vec<(String, ArchivedFile)>>>()?;
+        files.sort_by_key(|(_, file)| file.time);
+
+        let mut total_bytes: u64 = files.iter().map(|(_, file)| file.size).sum();
+        let mut removed = 0;
+        for (md5, file) in files {
+            let expired = retention_secs.is_some_and(|secs| file.time + secs < now);
+            let oversized = max_total_bytes.is_some_and(|max| total_bytes > max);
+            if !expired && !oversized {
+                continue;
+            }
+            if let Err(err) = std::fs::remove_file(&file.path) {
+                if err.kind() != std::io::ErrorKind::NotFound {
+                    return Err(err.into());
+                }
+            }
+            // only succeeds when the date directory becomes empty
+            if let Some(dir) = Path::new(&file.path).parent() {
+                let _ = std::fs::remove_dir(dir);
+            }
+            self.files.remove(&md5)?;
+            total_bytes -= file.size;
+            removed += 1;
+        }
+
+        for item in self.records.iter() {
+            let (key, value) = item?;
+            let record: ArchiveRecord = serde_json::from_slice(&value)?;
+            if !self.files.contains_key(&record.md5)? {
+                self.records.remove(key)?;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+/// Periodically removes the images beyond the retention limits.
+pub fn spawn_sweeper() {
+    let config = &cfg::BOT_CONFIG.archive;
+    if config.retention_days.is_none() && config.max_total_bytes.is_none() {
+        return;
+    }
+    tokio::spawn(async {
+        let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+        loop {
+            interval.tick().await;
+            let config = &cfg::BOT_CONFIG.archive;
+            let result = tokio::task::spawn_blocking(|| {
+                ARCHIVE.sweep(
+                    utils::unix_timestamp(),
+                    config.retention_days.map(|days| days * 24 * 60 * 60),
+                    config.max_total_bytes,
+                )
+            })
+            .await;
+            match result {
+                Ok(Ok(0)) => {}
+                Ok(Ok(removed)) => info!("removed {} images from the archive", removed),
+                Ok(Err(err)) => error!("failed to sweep the archive: {:#?}", err),
+                Err(err) => error!("failed to sweep the archive: {:#?}", err),
+            }
+        }
+    });
+}
+
+pub async fn on_group_message(message: OneBotGroupMessage) -> Option<BotResponseAction> {
+    let OneBotGroupMessage {
+        message,
+        message_id,
+        group_id,
+        user_id,
+        ..
+    } = message;
+
+    if let Some(id) = message.trim().strip_prefix("archive-get") {
+        let message = handle_get_command(id.trim(), Some(group_id), user_id);
+        return Some(BotResponseAction::GroupMessage {
+            group_id,
+            message: format!("[CQ:reply,id={}]{}", message_id, message),
+        });
+    }
+
+    if !cfg::BOT_CONFIG.archive.groups.contains(&group_id) {
+        return None;
+    }
+    for image in IMAGE_REGEX.find_iter(&message) {
+        let md5 = match cache::extract_image_md5(image.as_str()) {
+            Some(md5) => md5,
+            None => continue,
+        };
+        let url = IMAGE_URL_REGEX
+            .captures(image.as_str())
+            .map(|caps| caps[1].to_string());
+        tokio::spawn(async move {
+            if let Err(err) = archive_image(group_id, user_id, message_id, &md5, url).await {
+                error!("failed to archive image {}: {:#?}", md5, err);
+            }
+        });
+    }
+    None
+}
+
+pub async fn on_private_message(message: OneBotPrivateMessage) -> Option<BotResponseAction> {
+    let OneBotPrivateMessage {
+        user_id, message, ..
+    } = message;
+    if user_id != cfg::BOT_CONFIG.admin_user_id {
+        return None;
+    }
+
+    let id = message.trim().strip_prefix("archive-get")?;
+    Some(BotResponseAction::PrivateMessage {
+        user_id,
+        message: handle_get_command(id.trim(), None, user_id),
+    })
+}
+
+async fn archive_image(
+    group_id: i64,
+    user_id: i64,
+    message_id: i32,
+    md5: &str,
+    url: Option<String>,
+) -> Result<()> {
+    let image = if ARCHIVE.has_file(md5)? {
+        None
+    } else {
+        let url = url.ok_or_else(|| anyhow!("image {} has no url", md5))?;
+        Some(searcher::fetch_image(&url).await?)
+    };
+    let record = ARCHIVE.store(
+        group_id,
+        user_id,
+        message_id,
+        md5,
+        image.as_deref(),
+        utils::unix_timestamp(),
+    )?;
+    if image.is_some() {
+        if let Some((_, path)) = ARCHIVE.get(record.id)? {
+            local_search::spawn_index_file(path);
+        }
+    }
+    Ok(())
+}
+
+/// Images archived in other groups are only visible to the admin.
+fn handle_get_command(id: &str, group_id: Option<i64>, user_id: i64) -> String {
+    let id = match id.parse::<u64>() {
+        Ok(id) => id,
+        Err(_) => return "用法: archive-get <id>".to_string(),
+    };
+    match ARCHIVE.get(id) {
+        Ok(Some((record, path)))
+            if group_id.is_none_or(|group_id| group_id == record.group_id)
+                || user_id == cfg::BOT_CONFIG.admin_user_id =>
+        {
+            format!(
+                "存档 {}：群 {} 中 {} 于 {} 发送\n[CQ:image,file=file://{}]",
+                record.id,
+                record.group_id,
+                record.user_id,
+                utils::format_timestamp(record.time),
+                path
+            )
+        }
+        Ok(_) => format!("找不到存档图片 {}", id),
+        Err(err) => {
+            warn!("failed to get archived image {}: {:#?}", id, err);
+            format!("获取存档图片时出错: {:#?}", err)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::archive::Archive;
+    use std::io::Cursor;
+    use std::path::Path;
+
+    fn png() -> Vec<u8> {
+        let mut png = Cursor::new(vec![]);
+        image::DynamicImage::ImageRgb8(image::RgbImage::new(16, 16))
+            .write_to(&mut png, image::ImageOutputFormat::Png)
+            .unwrap();
+        png.into_inner()
+    }
+
+    #[test]
+    fn archive_test() {
+        let root = std::env::temp_dir().join(format!("archive_{}", std::process::id()));
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let archive = Archive::open(&db, &root).unwrap();
+        let image = png();
+
+        let first = archive
+            .store(1, 10, 100, "aaaa", Some(&image), 1_000_000)
+            .unwrap();
+        assert!(archive.has_file("aaaa").unwrap());
+        // a duplicate is recorded without storing the image again
+        let second = archive.store(2, 20, 200, "aaaa", None, 1_100_000).unwrap();
+        assert!(archive.store(1, 10, 101, "bbbb", None, 1_100_000).is_err());
+        archive
+            .store(1, 10, 102, "cccc", Some(&image), 1_200_000)
+            .unwrap();
+
+        let (record, path) = archive.get(second.id).unwrap().unwrap();
+        assert_eq!(record.group_id, 2);
+        assert!(path.starts_with(root.join("1").to_str().unwrap()));
+        assert!(path.ends_with("aaaa.png"));
+        assert!(Path::new(&path).exists());
+        assert_eq!(archive.first_record("aaaa").unwrap(), Some(first));
+
+        // aaaa was last posted at 1_100_000
+        assert_eq!(archive.sweep(1_150_000, Some(60_000), None).unwrap(), 0);
+        assert_eq!(archive.sweep(1_150_000, Some(10_000), None).unwrap(), 1);
+        assert!(!Path::new(&path).exists());
+        assert!(archive.get(second.id).unwrap().is_none());
+        assert!(archive.first_record("cccc").unwrap().is_some());
+        assert_eq!(archive.sweep(1_150_000, None, Some(0)).unwrap(), 1);
+        assert!(archive.first_record("cccc").unwrap().is_none());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
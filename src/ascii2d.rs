@@ -1,11 +1,37 @@
+use crate::cfg;
 use crate::client::CLIENT;
 use crate::searcher::*;
 use anyhow::anyhow;
 use async_trait::async_trait;
+use log::warn;
+use reqwest::multipart::Form;
+use reqwest::Response;
 use std::collections::HashMap;
 use visdom::Vis;
 
-pub struct Ascii2dImageSearcher {}
+pub struct Ascii2dImageSearcher {
+    pub config: cfg::SearcherConfig,
+}
+
+/// A single search mode often returns look-alikes, so its hits rank below strong direct matches.
+const ASCII2D_SIMILARITY: f64 = 0.6;
+/// Similarity of hits found by both the color and the feature search.
+const ASCII2D_AGREED_SIMILARITY: f64 = 0.75;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SearchMode {
+    Color,
+    Bovw,
+}
+
+impl SearchMode {
+    fn label(&self) -> &'static str {
+        match self {
+            SearchMode::Color => "色合",
+            SearchMode::Bovw => "特征",
+        }
+    }
+}
 
 #[async_trait]
 impl ImageSearcher for Ascii2dImageSearcher {
@@ -13,39 +39,282 @@ impl ImageSearcher for Ascii2dImageSearcher {
         "ascii2d"
     }
 
+    fn get_config(&self) -> &cfg::SearcherConfig {
+        &self.config
+    }
+
     async fn search(&self, url: &str) -> ImageSearchResult {
         let response = CLIENT
             .get(format!("https://ascii2d.net/search/url/{}", url))
             .send()
             .await?
             .error_for_status()?;
-        let html = response.text().await?;
-        self.parse_result(html.as_str())
+        self.search_modes(response).await
+    }
+
+    async fn search_bytes(&self, image: &[u8]) -> ImageSearchResult {
+        let response = CLIENT
+            .post("https://ascii2d.net/search/file")
+            .multipart(Form::new().part("file", image_part(image)))
+            .send()
+            .await?
+            .error_for_status()?;
+        self.search_modes(response).await
     }
 }
 
 impl Ascii2dImageSearcher {
-    fn parse_result(&self, html: &str) -> ImageSearchResult {
+    /// Follows the color result page, e.g. `/search/color/{hash}`, to the feature one.
+    async fn search_modes(&self, response: Response) -> ImageSearchResult {
+        let color_url = response.url().to_string();
+        let html = response.text().await?;
+        let color_images = self.parse_result(html.as_str(), SearchMode::Color)?;
+
+        if !color_url.contains("/color/") {
+            warn!("unexpected ascii2d result page {}", color_url);
+            return Ok(color_images);
+        }
+        let bovw_images = match self
+            .fetch_result(&color_url.replacen("/color/", "/bovw/", 1))
+            .await
+        {
+            Ok(html) => self.parse_result(html.as_str(), SearchMode::Bovw)?,
+            Err(err) => {
+                warn!("failed to get ascii2d feature search result: {:#?}", err);
+                vec![]
+            }
+        };
+        Ok(merge_modes(color_images, bovw_images))
+    }
+
+    async fn fetch_result(&self, url: &str) -> anyhow::Result<String> {
+        Ok(CLIENT
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?)
+    }
+
+    fn parse_result(&self, html: &str, mode: SearchMode) -> ImageSearchResult {
         let root = Vis::load(html).map_err(|e| anyhow!(e))?;
-        let item_box = root.find(".item-box ~ .item-box");
-        let source_url = item_box
-            .find(".detail-box a:nth-of-type(1)")
-            .attr("href")
-            .ok_or_else(|| anyhow!("failed to find href for ascii2d result"))?
-            .to_string();
-        let metadata = {
-            let name = item_box.find(".detail-box a:nth-of-type(2)").html();
-            if name.is_empty() {
-                HashMap::default()
-            } else {
-                HashMap::from([("作者".to_string(), name)])
+        // the first item box is the image being searched
+        let item_boxes = root.find(".item-box ~ .item-box");
+        let mut images = vec![];
+        for i in 0..item_boxes.length() {
+            let item_box = item_boxes.eq(i);
+            let links = item_box.find(".detail-box h6 a");
+            let source_url = match links.eq(0).attr("href") {
+                Some(href) => href.to_string(),
+                None => continue,
+            };
+
+            let mut metadata: HashMap<String, String> =
+                HashMap::from([("模式".to_string(), mode.label().to_string())]);
+            let mut insert = |key: &str, value: String| {
+                let value = value.trim();
+                if !value.is_empty() {
+                    metadata.insert(key.to_string(), value.to_string());
+                }
+            };
+            insert("标题", links.eq(0).text());
+            insert("作者", links.eq(1).text());
+            if let Some(href) = links.eq(1).attr("href") {
+                insert("作者链接", href.to_string());
+            }
+            let source_type = match item_box.find(".detail-box h6 small").text() {
+                source_type if source_type.trim().is_empty() => item_box
+                    .find(".detail-box h6 img")
+                    .attr("alt")
+                    .map(|alt| alt.to_string())
+                    .unwrap_or_default(),
+                source_type => source_type,
+            };
+            insert("来源", source_type);
+            insert("缩略图哈希", item_box.find(".hash").text());
+
+            images.push(SourceImage {
+                url: source_url,
+                searcher: self.get_name(),
+                similarity: ASCII2D_SIMILARITY,
+                low_confidence: false,
+                metadata,
+                preview: None,
+            });
+        }
+        Ok(images)
+    }
+}
+
+/// Marks hits found by both search modes, which are more likely to be the source.
+fn merge_modes(color: Vec<SourceImage>, bovw: Vec<SourceImage>) -> Vec<SourceImage> {
+    let mut images = color;
+    for image in bovw {
+        match images.iter_mut().find(|found| found.url == image.url) {
+            Some(found) => {
+                found.similarity = ASCII2D_AGREED_SIMILARITY;
+                found.metadata.insert(
+                    "模式".to_string(),
+                    format!(
+                        "{}, {}",
+                        SearchMode::Color.label(),
+                        SearchMode::Bovw.label()
+                    ),
+                );
             }
+            None => images.push(image),
+        }
+    }
+    images
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ascii2d::{merge_modes, Ascii2dImageSearcher, SearchMode};
+    use crate::cfg::SearcherConfig;
+
+    static COLOR_RESULT: &str = r###"<!DOCTYPE html>
+<html>
+<head><title>二次元画像詳細検索</title></head>
+<body>
+<div class="container">
+<div class="row">
+<div class="col-xs-12 col-lg-8 col-xl-8">
+<h5 class="p-t-1 text-xs-center">色合検索</h5>
+<hr>
+<div class="row item-box">
+<div class="col-xs-12 col-sm-12 col-md-4 col-xl-4 text-xs-center image-box">
+<img loading="lazy" src="/thumbnail/0/1/2/3/0123456789abcdef0123456789abcdef.jpg" alt="0123456789abcdef0123456789abcdef">
+</div>
+<div class="col-xs-12 col-sm-12 col-md-8 col-xl-8 info-box">
+<div class="hash">0123456789abcdef0123456789abcdef</div>
+<small class="text-muted">1200x1600 JPEG 300.5KB</small>
+<div class="detail-box gray-link"></div>
+</div>
+</div>
+<hr>
+<div class="row item-box">
+<div class="col-xs-12 col-sm-12 col-md-4 col-xl-4 text-xs-center image-box">
+<img loading="lazy" src="/thumbnail/a/b/c/d/abcdef0123456789abcdef0123456789.jpg" alt="abcdef0123456789abcdef0123456789">
+</div>
+<div class="col-xs-12 col-sm-12 col-md-8 col-xl-8 info-box">
+<div class="hash">abcdef0123456789abcdef0123456789</div>
+<small class="text-muted">2400x3200 PNG 5210.3KB</small>
+<div class="detail-box gray-link">
+<h6>
+<img src="/assets/pixiv.ico" width="14" height="14" alt="pixiv">
+<a target="_blank" rel="noopener" href="https://www.pixiv.net/artworks/99118150">夏</a>
+<a target="_blank" rel="noopener" href="https://www.pixiv.net/users/1234">foo</a>
+<small>
+pixiv
+</small>
+</h6>
+</div>
+</div>
+</div>
+<hr>
+<div class="row item-box">
+<div class="col-xs-12 col-sm-12 col-md-4 col-xl-4 text-xs-center image-box">
+<img loading="lazy" src="/thumbnail/f/e/d/c/fedcba9876543210fedcba9876543210.jpg" alt="fedcba9876543210fedcba9876543210">
+</div>
+<div class="col-xs-12 col-sm-12 col-md-8 col-xl-8 info-box">
+<div class="hash">fedcba9876543210fedcba9876543210</div>
+<small class="text-muted">1200x1600 JPEG 250.1KB</small>
+<div class="detail-box gray-link">
+<h6>
+<img src="/assets/twitter.ico" width="14" height="14" alt="twitter">
+<a target="_blank" rel="noopener" href="https://twitter.com/foo/status/1576244843281797120">2022/10/02</a>
+<a target="_blank" rel="noopener" href="https://twitter.com/intent/user?user_id=5678">foo</a>
+</h6>
+</div>
+</div>
+</div>
+</div>
+</div>
+</div>
+</body>
+</html>"###;
+
+    static BOVW_RESULT: &str = r###"<!DOCTYPE html>
+<html>
+<body>
+<div class="container">
+<h5 class="p-t-1 text-xs-center">特徴検索</h5>
+<div class="row item-box">
+<div class="hash">0123456789abcdef0123456789abcdef</div>
+<div class="detail-box gray-link"></div>
+</div>
+<div class="row item-box">
+<div class="hash">1111222233334444aaaabbbbccccdddd</div>
+<div class="detail-box gray-link">
+<h6>
+<img src="/assets/fanbox.ico" width="14" height="14" alt="fanbox">
+<a target="_blank" rel="noopener" href="https://foo.fanbox.cc/posts/123456">差分</a>
+<a target="_blank" rel="noopener" href="https://foo.fanbox.cc/">foo</a>
+<small>fanbox</small>
+</h6>
+</div>
+</div>
+<div class="row item-box">
+<div class="hash">abcdef0123456789abcdef0123456789</div>
+<div class="detail-box gray-link">
+<h6>
+<img src="/assets/pixiv.ico" width="14" height="14" alt="pixiv">
+<a target="_blank" rel="noopener" href="https://www.pixiv.net/artworks/99118150">夏</a>
+<a target="_blank" rel="noopener" href="https://www.pixiv.net/users/1234">foo</a>
+<small>pixiv</small>
+</h6>
+</div>
+</div>
+</div>
+</body>
+</html>"###;
+
+    #[test]
+    fn parse_ascii2d_color_result() {
+        let searcher = Ascii2dImageSearcher {
+            config: SearcherConfig::ascii2d(),
+        };
+        let images = searcher
+            .parse_result(COLOR_RESULT, SearchMode::Color)
+            .unwrap();
+        assert_eq!(images.len(), 2);
+        assert_eq!(images[0].url, "https://www.pixiv.net/artworks/99118150");
+        assert_eq!(images[0].metadata["标题"], "夏");
+        assert_eq!(images[0].metadata["作者"], "foo");
+        assert_eq!(
+            images[0].metadata["作者链接"],
+            "https://www.pixiv.net/users/1234"
+        );
+        assert_eq!(images[0].metadata["来源"], "pixiv");
+        assert_eq!(
+            images[0].metadata["缩略图哈希"],
+            "abcdef0123456789abcdef0123456789"
+        );
+        assert_eq!(images[0].metadata["模式"], "色合");
+        assert_eq!(images[1].metadata["来源"], "twitter");
+    }
+
+    #[test]
+    fn parse_ascii2d_bovw_result() {
+        let searcher = Ascii2dImageSearcher {
+            config: SearcherConfig::ascii2d(),
         };
+        let color = searcher
+            .parse_result(COLOR_RESULT, SearchMode::Color)
+            .unwrap();
+        let bovw = searcher
+            .parse_result(BOVW_RESULT, SearchMode::Bovw)
+            .unwrap();
+        assert_eq!(bovw.len(), 2);
+        assert_eq!(bovw[0].metadata["来源"], "fanbox");
+        assert_eq!(bovw[0].metadata["模式"], "特征");
 
-        Ok(Some(SourceImage {
-            url: source_url,
-            searcher: self.get_name(),
-            metadata,
-        }))
+        let images = merge_modes(color, bovw);
+        assert_eq!(images.len(), 3);
+        assert_eq!(images[0].metadata["模式"], "色合, 特征");
+        assert!(images[0].similarity > images[1].similarity);
+        assert_eq!(images[2].url, "https://foo.fanbox.cc/posts/123456");
     }
 }
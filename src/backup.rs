@@ -0,0 +1,354 @@
+use crate::database::*;
+use crate::message::*;
+use crate::storage::SCHEMA_VERSION;
+use crate::{cfg, utils};
+use anyhow::{anyhow, bail, Result};
+use chrono::Local;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::{error, info};
+use nanoid::nanoid;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// The first line of a dump.
+#[derive(Debug, Serialize, Deserialize)]
+struct DumpHeader {
+    schema_version: u32,
+    time: u64,
+}
+
+/// A key-value pair of a tree, with the bytes encoded in hex.
+#[derive(Debug, Serialize, Deserialize)]
+struct DumpEntry {
+    tree: String,
+    key: String,
+    value: String,
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        bail!("malformed hex string {}", hex);
+    }
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| Ok(u8::from_str_radix(std::str::from_utf8(pair)?, 16)?))
+        .collect()
+}
+
+/// Writes every tree of the database as JSON lines, returns the number of entries.
+pub fn export(db: &sled::Db, writer: impl Write) -> Result<usize> {
+    let mut writer = BufWriter::new(writer);
+    let header = DumpHeader {
+        schema_version: SCHEMA_VERSION,
+        time: utils::unix_timestamp(),
+    };
+    writeln!(writer, "{}", serde_json::to_string(&header)?)?;
+    let mut count = 0;
+    for name in db.tree_names() {
+        let tree = db.open_tree(&name)?;
+        let name = String::from_utf8(name.to_vec())?;
+        for item in tree.iter() {
+            let (key, value) = item?;
+            let entry = DumpEntry {
+                tree: name.clone(),
+                key: to_hex(&key),
+                value: to_hex(&value),
+            };
+            writeln!(writer, "{}", serde_json::to_string(&entry)?)?;
+            count += 1;
+        }
+    }
+    writer.flush()?;
+    Ok(count)
+}
+
+/// Replaces the content of the database with a dump, returns the number of entries.
+pub fn import(db: &sled::Db, reader: impl BufRead) -> Result<usize> {
+    let mut lines = reader.lines();
+    let header: DumpHeader =
+        serde_json::from_str(&lines.next().ok_or_else(|| anyhow!("the dump is empty"))??)?;
+    if header.schema_version > SCHEMA_VERSION {
+        bail!(
+            "the dump is at schema version {}, newer than {}",
+            header.schema_version,
+            SCHEMA_VERSION
+        );
+    }
+
+    // the whole dump is parsed first, so that a malformed one leaves the database untouched
+    let mut batches: BTreeMap<String, sled::Batch> = BTreeMap::new();
+    let mut count = 0;
+    for line in lines {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let entry: DumpEntry = serde_json::from_str(&line)?;
+        batches
+            .entry(entry.tree)
+            .or_default()
+            .insert(from_hex(&entry.key)?, from_hex(&entry.value)?);
+        count += 1;
+    }
+
+    // the current content is restored if the import fails halfway
+    let previous = snapshot(db)?;
+    if let Err(err) = replace(db, batches) {
+        error!(
+            "failed to import the dump, restoring the database: {:#?}",
+            err
+        );
+        replace(db, previous)?;
+        return Err(err);
+    }
+    Ok(count)
+}
+
+fn snapshot(db: &sled::Db) -> Result<BTreeMap<String, sled::Batch>> {
+    let mut batches: BTreeMap<String, sled::Batch> = BTreeMap::new();
+    for name in db.tree_names() {
+        let batch = batches
+            .entry(String::from_utf8(name.to_vec())?)
+            .or_default();
+        for item in db.open_tree(name)?.iter() {
+            let (key, value) = item?;
+            batch.insert(key, value);
+        }
+    }
+    Ok(batches)
+}
+
+fn replace(db: &sled::Db, batches: BTreeMap<String, sled::Batch>) -> Result<()> {
+    for name in db.tree_names() {
+        db.open_tree(name)?.clear()?;
+    }
+    for (name, batch) in batches {
+        db.open_tree(name)?.apply_batch(batch)?;
+    }
+    db.flush()?;
+    Ok(())
+}
+
+/// Exports the database into a new gzipped file in the directory, and removes the oldest
+/// backups beyond `keep`.
+pub fn backup(db: &sled::Db, dir: &Path, keep: usize) -> Result<PathBuf> {
+    std::fs::create_dir_all(dir)?;
+    // the suffix tells apart the backups made within a second
+    let path = dir.join(format!(
+        "db-{}-{}.jsonl.gz",
+        Local::now().format("%Y%m%d-%H%M%S"),
+        nanoid!(6)
+    ));
+    let mut encoder = GzEncoder::new(File::create(&path)?, Compression::default());
+    export(db, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+
+    // the names sort by time
+    let mut backups = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("db-") && name.ends_with(".jsonl.gz"))
+        })
+        .collect::<Vec<PathBuf>>();
+    backups.sort();
+    for old in backups.iter().take(backups.len().saturating_sub(keep)) {
+        std::fs::remove_file(old)?;
+    }
+    Ok(path)
+}
+
+/// Opens a dump, which is gzipped if its name ends with `.gz`.
+pub fn open_dump(path: &Path) -> Result<Box<dyn BufRead>> {
+    let file = File::open(path)?;
+    if path.extension().is_some_and(|extension| extension == "gz") {
+        Ok(Box::new(BufReader::new(GzDecoder::new(file))))
+    } else {
+        Ok(Box::new(BufReader::new(file)))
+    }
+}
+
+/// Handles `export <file>` and `import <file>` in the command line arguments, the file is
+/// gzipped if its name ends with `.gz`.
+pub fn run_command(args: &[String]) -> Option<Result<String>> {
+    let (command, path) = match args {
+        [command, path] if command == "export" || command == "import" => {
+            (command.as_str(), Path::new(path))
+        }
+        _ => return None,
+    };
+    let result = if command == "export" {
+        File::create(path)
+            .map_err(anyhow::Error::from)
+            .and_then(|file| {
+                let count = if path.extension().is_some_and(|extension| extension == "gz") {
+                    let mut encoder = GzEncoder::new(file, Compression::default());
+                    let count = export(&DATABASE, &mut encoder)?;
+                    encoder.finish()?.sync_all()?;
+                    count
+                } else {
+                    export(&DATABASE, &file)?
+                };
+                Ok(format!("exported {} entries to {}", count, path.display()))
+            })
+    } else {
+        open_dump(path)
+            .and_then(|dump| import(&DATABASE, dump))
+            .map(|count| format!("imported {} entries from {}", count, path.display()))
+    };
+    Some(result)
+}
+
+async fn backup_now() -> Result<PathBuf> {
+    tokio::task::spawn_blocking(|| {
+        let config = &cfg::BOT_CONFIG.backup;
+        // the path is sent to the OneBot implementation, which may run in another directory
+        Ok(backup(&DATABASE, Path::new(&config.path), config.keep)?.canonicalize()?)
+    })
+    .await?
+}
+
+pub fn spawn_scheduler() {
+    let interval_hours = match cfg::BOT_CONFIG.backup.interval_hours {
+        Some(interval_hours) => interval_hours,
+        None => return,
+    };
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(interval_hours * 60 * 60));
+        // the first tick completes immediately
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            match backup_now().await {
+                Ok(path) => info!("backed up the database to {}", path.display()),
+                Err(err) => error!("failed to back up the database: {:#?}", err),
+            }
+        }
+    });
+}
+
+pub async fn on_group_message(message: OneBotGroupMessage) -> Option<BotResponseAction> {
+    let OneBotGroupMessage {
+        message,
+        user_id,
+        group_id,
+        ..
+    } = message;
+    if user_id != cfg::BOT_CONFIG.admin_user_id || message.trim() != "db-backup" {
+        return None;
+    }
+
+    Some(match backup_now().await {
+        Ok(path) => BotResponseAction::GroupFile {
+            group_id,
+            file: path.to_string_lossy().to_string(),
+            name: path.file_name()?.to_str()?.to_string(),
+        },
+        Err(err) => BotResponseAction::GroupMessage {
+            group_id,
+            message: format!("备份数据库时出错: {:#?}", err),
+        },
+    })
+}
+
+pub async fn on_private_message(message: OneBotPrivateMessage) -> Option<BotResponseAction> {
+    let OneBotPrivateMessage {
+        user_id, message, ..
+    } = message;
+    if user_id != cfg::BOT_CONFIG.admin_user_id || message.trim() != "db-backup" {
+        return None;
+    }
+
+    Some(match backup_now().await {
+        Ok(path) => BotResponseAction::PrivateFile {
+            user_id,
+            file: path.to_string_lossy().to_string(),
+            name: path.file_name()?.to_str()?.to_string(),
+        },
+        Err(err) => BotResponseAction::PrivateMessage {
+            user_id,
+            message: format!("备份数据库时出错: {:#?}", err),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backup::{backup, export, from_hex, import, open_dump, to_hex};
+    use std::io::Cursor;
+
+    #[test]
+    fn hex_test() {
+        assert_eq!(to_hex(&[0, 15, 255]), "000fff");
+        assert_eq!(from_hex("000fff").unwrap(), vec![0, 15, 255]);
+        assert!(from_hex("0").is_err());
+        assert!(from_hex("zz").is_err());
+        assert!(from_hex("é0").is_err());
+    }
+
+    #[test]
+    fn export_import_test() {
+        let source = sled::Config::new().temporary(true).open().unwrap();
+        source.insert("image_url:1", "a").unwrap();
+        let tree = source.open_tree("messages").unwrap();
+        tree.insert([0u8, 1, 255], r#"{"url":"b"}"#).unwrap();
+        tree.insert("c", vec![]).unwrap();
+
+        let mut dump = vec![];
+        assert_eq!(export(&source, &mut dump).unwrap(), 3);
+
+        let target = sled::Config::new().temporary(true).open().unwrap();
+        target.insert("stale", "d").unwrap();
+        assert_eq!(import(&target, Cursor::new(&dump)).unwrap(), 3);
+        assert_eq!(target.get("stale").unwrap(), None);
+        assert_eq!(target.get("image_url:1").unwrap().unwrap(), "a");
+        let tree = target.open_tree("messages").unwrap();
+        assert_eq!(tree.get([0u8, 1, 255]).unwrap().unwrap(), r#"{"url":"b"}"#);
+        assert_eq!(tree.get("c").unwrap().unwrap(), "");
+
+        let newer = r#"{"schema_version":4294967295,"time":0}"#;
+        assert!(import(&target, Cursor::new(newer)).is_err());
+        let malformed = format!("{}\nnot an entry", String::from_utf8(dump).unwrap());
+        assert!(import(&target, Cursor::new(malformed)).is_err());
+        assert_eq!(target.get("image_url:1").unwrap().unwrap(), "a");
+    }
+
+    #[test]
+    fn backup_test() {
+        let dir = std::env::temp_dir().join(format!("backup_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in [
+            "db-20220101-000000.jsonl.gz",
+            "db-20220102-000000.jsonl.gz",
+            "other",
+        ] {
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+        let source = sled::Config::new().temporary(true).open().unwrap();
+        source.insert("key", "value").unwrap();
+
+        let path = backup(&source, &dir, 3).unwrap();
+        assert_ne!(backup(&source, &dir, 3).unwrap(), path);
+        assert!(!dir.join("db-20220101-000000.jsonl.gz").exists());
+        assert!(dir.join("db-20220102-000000.jsonl.gz").exists());
+        assert!(dir.join("other").exists());
+
+        let target = sled::Config::new().temporary(true).open().unwrap();
+        assert_eq!(import(&target, open_dump(&path).unwrap()).unwrap(), 1);
+        assert_eq!(target.get("key").unwrap().unwrap(), "value");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
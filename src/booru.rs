@@ -0,0 +1,278 @@
+use crate::aggregator::AggregatedImage;
+use crate::client::CLIENT;
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use log::error;
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Booru {
+    Danbooru,
+    Gelbooru,
+    Yandere,
+    Konachan,
+}
+
+#[derive(Deserialize, Debug)]
+struct DanbooruPost {
+    tag_string_artist: String,
+    tag_string_character: String,
+    tag_string_copyright: String,
+    rating: Option<String>,
+    source: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct GelbooruResponse {
+    post: Option<Vec<MoebooruPost>>,
+}
+
+/// Posts of yande.re and konachan, gelbooru returns posts of the same shape.
+#[derive(Deserialize, Debug)]
+struct MoebooruPost {
+    tags: String,
+    rating: String,
+    source: Option<String>,
+}
+
+impl Booru {
+    fn base_url(&self) -> &'static str {
+        match self {
+            Booru::Danbooru => "https://danbooru.donmai.us",
+            Booru::Gelbooru => "https://gelbooru.com",
+            Booru::Yandere => "https://yande.re",
+            Booru::Konachan => "https://konachan.com",
+        }
+    }
+
+    fn rating_name(&self, rating: &str) -> String {
+        match (self, rating) {
+            (_, "g" | "general") => "general",
+            (Booru::Danbooru, "s") | (_, "sensitive") => "sensitive",
+            (_, "s" | "safe") => "safe",
+            (_, "q" | "questionable") => "questionable",
+            (_, "e" | "explicit") => "explicit",
+            (_, rating) => rating,
+        }
+        .to_string()
+    }
+}
+
+pub fn match_post(url: &str) -> Option<(Booru, String)> {
+    lazy_static! {
+        static ref POST_REGEXES: [(Booru, Regex); 4] = [
+            (
+                Booru::Danbooru,
+                Regex::new(r"^https?://danbooru\.donmai\.us/(?:posts|post/show)/(\d+)").unwrap()
+            ),
+            (
+                Booru::Gelbooru,
+                Regex::new(r"^https?://gelbooru\.com/index\.php\?.*\bid=(\d+)").unwrap()
+            ),
+            (
+                Booru::Yandere,
+                Regex::new(r"^https?://yande\.re/post/show/(\d+)").unwrap()
+            ),
+            (
+                Booru::Konachan,
+                Regex::new(r"^https?://konachan\.(?:com|net)/post/show/(\d+)").unwrap()
+            ),
+        ];
+    }
+
+    POST_REGEXES.iter().find_map(|(booru, regex)| {
+        regex
+            .captures(url)
+            .map(|caps| (*booru, caps[1].to_string()))
+    })
+}
+
+/// Adds the tags, rating and original source of booru posts to their metadata.
+pub async fn enrich(images: &mut [AggregatedImage]) {
+    let tasks = images.iter_mut().filter_map(|image| {
+        let (booru, id) = match_post(&image.url)?;
+        Some(async move {
+            match fetch_post(booru, booru.base_url(), &id).await {
+                Ok(metadata) => image.metadata.extend(metadata),
+                Err(err) => error!("failed to fetch booru post {}: {:#?}", image.url, err),
+            }
+        })
+    });
+    futures::future::join_all(tasks).await;
+}
+
+async fn fetch_post(booru: Booru, base_url: &str, id: &str) -> Result<HashMap<String, String>> {
+    let mut metadata: HashMap<String, String> = HashMap::new();
+    let mut insert = |key: &str, value: String| {
+        let value = value.split_whitespace().collect::<Vec<&str>>().join(", ");
+        if !value.is_empty() {
+            metadata.insert(key.to_string(), value);
+        }
+    };
+
+    match booru {
+        Booru::Danbooru => {
+            let post: DanbooruPost = CLIENT
+                .get(format!("{}/posts/{}.json", base_url, id))
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            insert("画师", post.tag_string_artist);
+            insert("角色", post.tag_string_character);
+            insert("作品", post.tag_string_copyright);
+            if let Some(rating) = post.rating {
+                insert("分级", booru.rating_name(&rating));
+            }
+            insert("原始出处", post.source);
+        }
+        Booru::Gelbooru | Booru::Yandere | Booru::Konachan => {
+            let posts: Vec<MoebooruPost> = if booru == Booru::Gelbooru {
+                let response: GelbooruResponse = CLIENT
+                    .get(format!("{}/index.php", base_url))
+                    .query(&[
+                        ("page", "dapi"),
+                        ("s", "post"),
+                        ("q", "index"),
+                        ("json", "1"),
+                        ("id", id),
+                    ])
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await?;
+                response.post.unwrap_or_default()
+            } else {
+                CLIENT
+                    .get(format!("{}/post.json", base_url))
+                    .query(&[("tags", format!("id:{}", id))])
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await?
+            };
+            let post = posts
+                .into_iter()
+                .next()
+                .ok_or_else(|| anyhow!("post {} not found", id))?;
+            insert("标签", post.tags);
+            insert("分级", booru.rating_name(&post.rating));
+            insert("原始出处", post.source.unwrap_or_default());
+        }
+    }
+
+    Ok(metadata)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::booru::{fetch_post, match_post, Booru};
+    use wiremock::matchers::{method, path, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[test]
+    fn match_post_test() {
+        assert_eq!(
+            match_post("https://danbooru.donmai.us/posts/5678901"),
+            Some((Booru::Danbooru, "5678901".to_string()))
+        );
+        assert_eq!(
+            match_post("https://gelbooru.com/index.php?page=post&s=view&id=7654321"),
+            Some((Booru::Gelbooru, "7654321".to_string()))
+        );
+        assert_eq!(
+            match_post("https://yande.re/post/show/1002003"),
+            Some((Booru::Yandere, "1002003".to_string()))
+        );
+        assert_eq!(
+            match_post("http://konachan.net/post/show/334455"),
+            Some((Booru::Konachan, "334455".to_string()))
+        );
+        assert_eq!(match_post("https://www.pixiv.net/artworks/99118150"), None);
+    }
+
+    #[tokio::test]
+    async fn fetch_danbooru_post() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/posts/5678901.json"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                r###"{"id":5678901,"rating":"s","source":"https://www.pixiv.net/artworks/99118150","tag_string_artist":"foo_(artist)","tag_string_character":"hatsune_miku kagamine_rin","tag_string_copyright":"vocaloid","tag_string_general":"1girl solo"}"###,
+            ))
+            .mount(&server)
+            .await;
+
+        let metadata = fetch_post(Booru::Danbooru, &server.uri(), "5678901")
+            .await
+            .unwrap();
+        assert_eq!(metadata["画师"], "foo_(artist)");
+        assert_eq!(metadata["角色"], "hatsune_miku, kagamine_rin");
+        assert_eq!(metadata["作品"], "vocaloid");
+        assert_eq!(metadata["分级"], "sensitive");
+        assert_eq!(
+            metadata["原始出处"],
+            "https://www.pixiv.net/artworks/99118150"
+        );
+    }
+
+    #[tokio::test]
+    async fn fetch_gelbooru_post() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/index.php"))
+            .and(query_param("id", "7654321"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                r###"{"@attributes":{"limit":100,"offset":0,"count":1},"post":[{"id":7654321,"tags":"1girl hatsune_miku vocaloid","rating":"general","source":""}]}"###,
+            ))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/index.php"))
+            .and(query_param("id", "1"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(r###"{"@attributes":{"limit":100,"offset":0,"count":0}}"###),
+            )
+            .mount(&server)
+            .await;
+
+        let metadata = fetch_post(Booru::Gelbooru, &server.uri(), "7654321")
+            .await
+            .unwrap();
+        assert_eq!(metadata["标签"], "1girl, hatsune_miku, vocaloid");
+        assert_eq!(metadata["分级"], "general");
+        assert!(!metadata.contains_key("原始出处"));
+
+        assert!(fetch_post(Booru::Gelbooru, &server.uri(), "1")
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn fetch_moebooru_post() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/post.json"))
+            .and(query_param("tags", "id:1002003"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                r###"[{"id":1002003,"tags":"dress seifuku","rating":"s","source":"https://twitter.com/foo/status/1576244843281797120"}]"###,
+            ))
+            .mount(&server)
+            .await;
+
+        let metadata = fetch_post(Booru::Yandere, &server.uri(), "1002003")
+            .await
+            .unwrap();
+        assert_eq!(metadata["标签"], "dress, seifuku");
+        assert_eq!(metadata["分级"], "safe");
+        assert_eq!(
+            metadata["原始出处"],
+            "https://twitter.com/foo/status/1576244843281797120"
+        );
+    }
+}
@@ -0,0 +1,124 @@
+use crate::aggregator::AggregatedImage;
+use crate::cfg;
+use crate::message::*;
+use crate::storage::STORAGE;
+use crate::utils;
+use anyhow::Result;
+use lazy_static::lazy_static;
+use log::error;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+lazy_static! {
+    static ref IMAGE_MD5_REGEX: Regex = Regex::new(r"file=([0-9a-fA-F]{32})").unwrap();
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CachedSearchResult {
+    time: u64,
+    images: Vec<AggregatedImage>,
+}
+
+/// Extracts the md5 of the first image from the `file` field of `[CQ:image]`.
+pub fn extract_image_md5(message: &str) -> Option<String> {
+    IMAGE_MD5_REGEX
+        .captures(message)
+        .map(|caps| caps[1].to_lowercase())
+}
+
+pub fn get(md5: &str) -> Option<Vec<AggregatedImage>> {
+    let cached = match STORAGE.search_cache.get(md5) {
+        Ok(cached) => cached?,
+        Err(err) => {
+            error!(
+                "failed to get search cache {} from database: {:#?}",
+                md5, err
+            );
+            return None;
+        }
+    };
+    if utils::unix_timestamp() > cached.time + cfg::BOT_CONFIG.search_cache_ttl_secs {
+        if let Err(err) = STORAGE.search_cache.remove(md5) {
+            error!("failed to remove expired search cache {}: {:#?}", md5, err);
+        }
+        return None;
+    }
+    Some(cached.images)
+}
+
+pub fn insert(md5: &str, images: &[AggregatedImage]) {
+    let cached = CachedSearchResult {
+        time: utils::unix_timestamp(),
+        images: images.to_vec(),
+    };
+    if let Err(err) = STORAGE.search_cache.insert(md5, &cached) {
+        error!("failed to insert search cache into database: {}", err);
+    }
+}
+
+pub async fn on_private_message(message: OneBotPrivateMessage) -> Option<BotResponseAction> {
+    let OneBotPrivateMessage {
+        user_id, message, ..
+    } = message;
+    if user_id != cfg::BOT_CONFIG.admin_user_id {
+        return None;
+    }
+
+    let message = handle_cache_command(message.trim())?;
+    Some(BotResponseAction::PrivateMessage { user_id, message })
+}
+
+pub async fn on_group_message(message: OneBotGroupMessage) -> Option<BotResponseAction> {
+    let OneBotGroupMessage {
+        message,
+        user_id,
+        group_id,
+        ..
+    } = message;
+    if user_id != cfg::BOT_CONFIG.admin_user_id {
+        return None;
+    }
+
+    let message = handle_cache_command(message.trim())?;
+    Some(BotResponseAction::GroupMessage { group_id, message })
+}
+
+fn handle_cache_command(message: &str) -> Option<String> {
+    let target = message.strip_prefix("cache-clear")?.trim();
+    let result = match target {
+        "all" => clear().map(|count| format!("已清除 {} 条搜索缓存", count)),
+        "" => return Some("用法: cache-clear <md5|all>".to_string()),
+        md5 => invalidate(&md5.to_lowercase()).map(|removed| {
+            if removed {
+                format!("已清除 {} 的搜索缓存", md5)
+            } else {
+                format!("{} 没有搜索缓存", md5)
+            }
+        }),
+    };
+    Some(result.unwrap_or_else(|err| format!("清除搜索缓存时出错: {:#?}", err)))
+}
+
+fn invalidate(md5: &str) -> Result<bool> {
+    STORAGE.search_cache.remove(md5)
+}
+
+fn clear() -> Result<usize> {
+    let count = STORAGE.search_cache.count();
+    STORAGE.search_cache.clear()?;
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cache::extract_image_md5;
+
+    #[test]
+    fn extract_image_md5_test() {
+        assert_eq!(
+            extract_image_md5("[CQ:image,file=5C0FD1F5E5A7D1B8D2BD62A3F7C3A1F0.image,subType=0,url=https://gchat.qpic.cn/gchatpic_new/1/2-3-5C0FD1F5E5A7D1B8D2BD62A3F7C3A1F0/0?term=2]"),
+            Some("5c0fd1f5e5a7d1b8d2bd62a3f7c3a1f0".to_string())
+        );
+        assert_eq!(extract_image_md5("[CQ:image,file=foo.image]"), None);
+    }
+}
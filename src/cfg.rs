@@ -1,5 +1,6 @@
 use lazy_static::lazy_static;
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
 
 lazy_static! {
     pub static ref BOT_CONFIG: BotConfig = config::Config::builder()
@@ -15,7 +16,455 @@ lazy_static! {
 pub struct BotConfig {
     pub ws_url: String,
     pub download_path: String,
+    #[allow(dead_code)]
     pub proxy_url: Option<String>,
     pub saucenao_api_key: String,
     pub admin_user_id: i64,
+    #[serde(default = "SearcherConfig::saucenao")]
+    pub saucenao: SearcherConfig,
+    #[serde(default = "SearcherConfig::ascii2d")]
+    pub ascii2d: SearcherConfig,
+    #[serde(default = "SearcherConfig::iqdb")]
+    pub iqdb: SearcherConfig,
+    /// Enables searching 3d.iqdb.org when present.
+    pub iqdb_3d: Option<SearcherConfig>,
+    /// e.g. `["danbooru", "yande.re"]`, all services are searched when empty. A site hosting
+    /// none of the services, e.g. 3d.iqdb.org for `["danbooru"]`, is not searched.
+    #[serde(default)]
+    pub iqdb_services: Vec<String>,
+    #[serde(default = "SearcherConfig::tracemoe")]
+    pub tracemoe: SearcherConfig,
+    #[serde(default)]
+    pub tracemoe_send_preview: bool,
+    #[serde(default = "SearcherConfig::ehentai")]
+    pub ehentai: SearcherConfig,
+    pub exhentai_cookie: Option<String>,
+    #[serde(default)]
+    pub pixiv: PixivConfig,
+    #[serde(default = "BotConfig::default_search_cache_ttl_secs")]
+    pub search_cache_ttl_secs: u64,
+    /// Consecutive failures after which a searcher is skipped for a cooldown.
+    #[serde(default = "BotConfig::default_breaker_failure_threshold")]
+    pub breaker_failure_threshold: u32,
+    #[serde(default = "BotConfig::default_breaker_cooldown_secs")]
+    pub breaker_cooldown_secs: u64,
+    #[serde(default)]
+    pub search_reply_mode: SearchReplyMode,
+    /// Similarity of a hit that is replied before all searchers finish.
+    #[serde(default = "BotConfig::default_early_reply_similarity")]
+    pub early_reply_similarity: f64,
+    #[serde(default)]
+    pub repost: RepostConfig,
+    #[serde(default)]
+    pub local_search: LocalSearchConfig,
+    #[serde(default)]
+    pub archive: ArchiveConfig,
+    #[serde(default)]
+    pub retention: RetentionConfig,
+    #[serde(default = "BotConfig::default_db_path")]
+    pub db_path: String,
+    #[serde(default)]
+    pub backup: BackupConfig,
+    /// Number of downloads running at the same time.
+    #[serde(
+        default = "BotConfig::default_download_concurrency",
+        deserialize_with = "BotConfig::deserialize_download_concurrency"
+    )]
+    pub download_concurrency: usize,
+    /// Minimum interval between progress updates of a download, no updates are sent when 0.
+    #[serde(default = "BotConfig::default_download_progress_interval_secs")]
+    pub download_progress_interval_secs: u64,
+    #[serde(default)]
+    pub download_guard: DownloadGuardConfig,
+    #[serde(default)]
+    pub naming: NamingConfig,
+}
+
+impl BotConfig {
+    fn default_breaker_failure_threshold() -> u32 {
+        3
+    }
+
+    fn default_breaker_cooldown_secs() -> u64 {
+        5 * 60
+    }
+
+    fn default_search_cache_ttl_secs() -> u64 {
+        3 * 24 * 60 * 60
+    }
+
+    fn default_early_reply_similarity() -> f64 {
+        0.9
+    }
+
+    fn default_download_concurrency() -> usize {
+        2
+    }
+
+    /// No download would ever start without a permit.
+    fn deserialize_download_concurrency<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<usize, D::Error> {
+        let concurrency = usize::deserialize(deserializer)?;
+        if concurrency == 0 {
+            return Err(serde::de::Error::custom(
+                "download_concurrency must be at least 1",
+            ));
+        }
+        Ok(concurrency)
+    }
+
+    fn default_download_progress_interval_secs() -> u64 {
+        30
+    }
+
+    fn default_db_path() -> String {
+        "db".to_string()
+    }
+}
+
+#[derive(Debug, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchReplyMode {
+    /// Reply once all searchers finish.
+    #[default]
+    All,
+    /// Reply the first confident hit right away, and the late results in another message.
+    Progressive,
+    /// Stop searching once a confident hit is found.
+    EarlyExit,
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct PixivConfig {
+    pub phpsessid: Option<String>,
+    /// Host replacing `i.pximg.net` when sending images, e.g. `i.pixiv.re`.
+    /// Images are downloaded with the Referer header instead when absent.
+    pub mirror: Option<String>,
+    /// Groups in which posted pixiv links are handled.
+    #[serde(default)]
+    pub groups: Vec<PixivGroupConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PixivGroupConfig {
+    pub group_id: i64,
+    #[serde(default = "PixivGroupConfig::default_max_pages")]
+    pub max_pages: usize,
+    #[serde(default)]
+    pub allow_r18: bool,
+}
+
+impl PixivGroupConfig {
+    fn default_max_pages() -> usize {
+        5
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RepostConfig {
+    /// Groups in which reposted images are replied.
+    #[serde(default)]
+    pub groups: Vec<i64>,
+    /// Maximum hamming distance between the dHashes of two images, at most 3.
+    #[serde(
+        default = "RepostConfig::default_max_distance",
+        deserialize_with = "RepostConfig::deserialize_max_distance"
+    )]
+    pub max_distance: u32,
+    /// Maximum hamming distance between the pHashes, which confirms a dHash match.
+    #[serde(default = "RepostConfig::default_max_phash_distance")]
+    pub max_phash_distance: u32,
+}
+
+impl RepostConfig {
+    fn default_max_distance() -> u32 {
+        3
+    }
+
+    /// Larger distances would silently miss matches in the index of the hashes.
+    fn deserialize_max_distance<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<u32, D::Error> {
+        let max_distance = u32::deserialize(deserializer)?;
+        if max_distance > crate::repost::MAX_DISTANCE {
+            return Err(serde::de::Error::custom(format!(
+                "repost.max_distance must be at most {}, got {}",
+                crate::repost::MAX_DISTANCE,
+                max_distance
+            )));
+        }
+        Ok(max_distance)
+    }
+
+    fn default_max_phash_distance() -> u32 {
+        8
+    }
+}
+
+impl Default for RepostConfig {
+    fn default() -> Self {
+        RepostConfig {
+            groups: vec![],
+            max_distance: RepostConfig::default_max_distance(),
+            max_phash_distance: RepostConfig::default_max_phash_distance(),
+        }
+    }
+}
+
+/// Templates of the paths of downloaded files relative to `download_path`, with the placeholders
+/// `{site}`, `{author}`, `{id}`, `{index}`, `{name}` and `{ext}`.
+#[derive(Debug, Deserialize)]
+pub struct NamingConfig {
+    #[serde(default = "NamingConfig::default_video")]
+    pub video: String,
+    #[serde(default = "NamingConfig::default_image")]
+    pub image: String,
+    #[serde(default = "NamingConfig::default_pixiv")]
+    pub pixiv: String,
+}
+
+impl NamingConfig {
+    fn default_video() -> String {
+        "videos/{site}/{name}.{ext}".to_string()
+    }
+
+    fn default_image() -> String {
+        "images/{site}/{name}.{ext}".to_string()
+    }
+
+    fn default_pixiv() -> String {
+        "pixiv/{author}/{id}_{index}.{ext}".to_string()
+    }
+}
+
+impl Default for NamingConfig {
+    fn default() -> Self {
+        NamingConfig {
+            video: NamingConfig::default_video(),
+            image: NamingConfig::default_image(),
+            pixiv: NamingConfig::default_pixiv(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DownloadGuardConfig {
+    /// Maximum size in bytes of a downloaded file.
+    #[serde(default = "DownloadGuardConfig::default_max_file_bytes")]
+    pub max_file_bytes: Option<u64>,
+    /// MIME types allowed to download, e.g. `video/mp4`, or `video/*` for all videos.
+    /// All types are allowed when empty, files without a type count as `application/octet-stream`.
+    #[serde(default = "DownloadGuardConfig::default_allowed_mime_types")]
+    pub allowed_mime_types: Vec<String>,
+    /// Total size in bytes of the files downloaded by a user.
+    pub user_quota_bytes: Option<u64>,
+    /// Total size in bytes of the files in `download_path`, including the images saved by the
+    /// searchers and the archive.
+    pub total_quota_bytes: Option<u64>,
+    /// Free space in bytes kept in `download_path` after a download.
+    #[serde(default = "DownloadGuardConfig::default_min_free_bytes")]
+    pub min_free_bytes: u64,
+}
+
+impl DownloadGuardConfig {
+    fn default_max_file_bytes() -> Option<u64> {
+        Some(2 * 1024 * 1024 * 1024)
+    }
+
+    fn default_allowed_mime_types() -> Vec<String> {
+        vec!["video/*".to_string()]
+    }
+
+    fn default_min_free_bytes() -> u64 {
+        1024 * 1024 * 1024
+    }
+}
+
+impl Default for DownloadGuardConfig {
+    fn default() -> Self {
+        DownloadGuardConfig {
+            max_file_bytes: DownloadGuardConfig::default_max_file_bytes(),
+            allowed_mime_types: DownloadGuardConfig::default_allowed_mime_types(),
+            user_quota_bytes: None,
+            total_quota_bytes: None,
+            min_free_bytes: DownloadGuardConfig::default_min_free_bytes(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BackupConfig {
+    /// Directory of the backups, named `db-<time>-<suffix>.jsonl.gz`.
+    #[serde(default = "BackupConfig::default_path")]
+    pub path: String,
+    /// Backs up the database periodically when present.
+    pub interval_hours: Option<u64>,
+    /// Number of the latest backups kept.
+    #[serde(default = "BackupConfig::default_keep")]
+    pub keep: usize,
+}
+
+impl BackupConfig {
+    fn default_path() -> String {
+        "backups".to_string()
+    }
+
+    fn default_keep() -> usize {
+        7
+    }
+}
+
+impl Default for BackupConfig {
+    fn default() -> Self {
+        BackupConfig {
+            path: BackupConfig::default_path(),
+            interval_hours: None,
+            keep: BackupConfig::default_keep(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RetentionConfig {
+    /// TTLs in seconds of the values in trees by the names of the trees, e.g. `messages`.
+    #[serde(default = "RetentionConfig::default_tree_ttls")]
+    pub tree_ttls: HashMap<String, u64>,
+    #[serde(default = "RetentionConfig::default_sweep_interval_secs")]
+    pub sweep_interval_secs: u64,
+}
+
+impl RetentionConfig {
+    fn default_tree_ttls() -> HashMap<String, u64> {
+        HashMap::from([("messages".to_string(), 7 * 24 * 60 * 60)])
+    }
+
+    fn default_sweep_interval_secs() -> u64 {
+        60 * 60
+    }
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        RetentionConfig {
+            tree_ttls: RetentionConfig::default_tree_ttls(),
+            sweep_interval_secs: RetentionConfig::default_sweep_interval_secs(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct ArchiveConfig {
+    /// Groups whose images are saved to `download_path/<group_id>/<date>/`.
+    #[serde(default)]
+    pub groups: Vec<i64>,
+    /// Images not posted again within this many days are removed.
+    pub retention_days: Option<u64>,
+    /// The least recently posted images are removed beyond this size.
+    pub max_total_bytes: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LocalSearchConfig {
+    /// Maximum hamming distance between the dHashes of the replied image and a local image.
+    #[serde(default = "LocalSearchConfig::default_max_distance")]
+    pub max_distance: u32,
+    #[serde(default = "LocalSearchConfig::default_max_results")]
+    pub max_results: usize,
+}
+
+impl LocalSearchConfig {
+    fn default_max_distance() -> u32 {
+        12
+    }
+
+    fn default_max_results() -> usize {
+        5
+    }
+}
+
+impl Default for LocalSearchConfig {
+    fn default() -> Self {
+        LocalSearchConfig {
+            max_distance: LocalSearchConfig::default_max_distance(),
+            max_results: LocalSearchConfig::default_max_results(),
+        }
+    }
+}
+
+/// Per-engine rules applied to the ranked results of an `ImageSearcher`.
+#[derive(Debug, Deserialize, Clone)]
+pub struct SearcherConfig {
+    /// Normalized similarity (0.0 - 1.0) below which a hit is considered low-confidence.
+    pub min_similarity: f64,
+    /// Drop low-confidence hits instead of labeling them in the reply.
+    #[serde(default)]
+    pub drop_low_confidence: bool,
+    /// Maximum number of hits kept from this engine.
+    pub max_results: usize,
+    #[serde(default = "SearcherConfig::default_timeout_secs")]
+    pub timeout_secs: u64,
+    /// Larger images are downscaled and re-encoded before being uploaded.
+    #[serde(default = "SearcherConfig::default_max_upload_bytes")]
+    pub max_upload_bytes: u64,
+}
+
+impl SearcherConfig {
+    fn default_timeout_secs() -> u64 {
+        8
+    }
+
+    fn default_max_upload_bytes() -> u64 {
+        5 * 1024 * 1024
+    }
+
+    pub(crate) fn saucenao() -> Self {
+        SearcherConfig {
+            min_similarity: 0.7,
+            drop_low_confidence: false,
+            max_results: 3,
+            timeout_secs: SearcherConfig::default_timeout_secs(),
+            max_upload_bytes: 15 * 1024 * 1024,
+        }
+    }
+
+    pub(crate) fn ascii2d() -> Self {
+        SearcherConfig {
+            min_similarity: 0.0,
+            drop_low_confidence: false,
+            max_results: 2,
+            timeout_secs: SearcherConfig::default_timeout_secs(),
+            max_upload_bytes: SearcherConfig::default_max_upload_bytes(),
+        }
+    }
+
+    pub(crate) fn iqdb() -> Self {
+        SearcherConfig {
+            min_similarity: 0.8,
+            drop_low_confidence: false,
+            max_results: 2,
+            timeout_secs: SearcherConfig::default_timeout_secs(),
+            max_upload_bytes: 8 * 1024 * 1024,
+        }
+    }
+
+    pub(crate) fn tracemoe() -> Self {
+        SearcherConfig {
+            min_similarity: 0.87,
+            drop_low_confidence: true,
+            max_results: 1,
+            timeout_secs: SearcherConfig::default_timeout_secs(),
+            max_upload_bytes: 25 * 1024 * 1024,
+        }
+    }
+
+    pub(crate) fn ehentai() -> Self {
+        SearcherConfig {
+            min_similarity: 0.0,
+            drop_low_confidence: false,
+            max_results: 2,
+            timeout_secs: SearcherConfig::default_timeout_secs(),
+            max_upload_bytes: 10 * 1024 * 1024,
+        }
+    }
 }
@@ -1,8 +1,8 @@
+use crate::cfg;
 use lazy_static::lazy_static;
 use std::sync::Arc;
 
 lazy_static! {
-    pub static ref DATABASE: Arc<sled::Db> = Arc::new(
-        sled::open("db").unwrap()
-    );
-}
\ No newline at end of file
+    pub static ref DATABASE: Arc<sled::Db> =
+        Arc::new(sled::open(&cfg::BOT_CONFIG.db_path).expect("failed to open database"));
+}
@@ -1,13 +1,14 @@
 use anyhow::{anyhow, Context, Result};
 use lazy_static::lazy_static;
-use nanoid::nanoid;
 use regex::Regex;
-use std::path::Path;
-use std::time::Duration;
 use visdom::Vis;
 
 use crate::client::*;
+use crate::download_guard;
+use crate::download_queue::{self, JobChat, JobKind};
 use crate::message::*;
+use crate::naming::{self, NameFields};
+use crate::utils::{OnProgress, SizeLimitExceeded};
 use crate::{cfg, utils};
 
 pub async fn on_private_message(message: OneBotPrivateMessage) -> Option<BotResponseAction> {
@@ -18,21 +19,9 @@ pub async fn on_private_message(message: OneBotPrivateMessage) -> Option<BotResp
         return None;
     }
 
-    let message = message.trim();
-    match handle_download_command(&message).await {
-        Some(Ok((size, _))) => Some(BotResponseAction::PrivateMessage {
-            user_id,
-            message: format!(
-                "视频保存成功，大小: {}",
-                human_bytes::human_bytes(size as f64)
-            ),
-        }),
-        Some(Err(err)) => Some(BotResponseAction::PrivateMessage {
-            user_id,
-            message: format!("保存视频时出错: {:#?}", err),
-        }),
-        None => None,
-    }
+    let message =
+        handle_download_command(message.trim(), user_id, JobChat::Private { user_id }).await?;
+    Some(BotResponseAction::PrivateMessage { user_id, message })
 }
 
 pub async fn on_group_message(message: OneBotGroupMessage) -> Option<BotResponseAction> {
@@ -46,32 +35,45 @@ pub async fn on_group_message(message: OneBotGroupMessage) -> Option<BotResponse
         return None;
     }
 
-    let message = message.trim();
-    match handle_download_command(message).await {
-        Some(Ok((_, path))) => Some(BotResponseAction::GroupFile {
-            group_id,
-            file: path.clone(),
-            name: Path::new(&path).file_name()?.to_str()?.to_string(),
-        }),
-        Some(Err(err)) => Some(BotResponseAction::GroupMessage {
-            group_id,
-            message: format!("保存视频时出错: {:#?}", err),
-        }),
-        None => None,
-    }
+    let message =
+        handle_download_command(message.trim(), user_id, JobChat::Group { group_id }).await?;
+    Some(BotResponseAction::GroupMessage { group_id, message })
 }
 
-async fn handle_download_command(message: &str) -> Option<Result<(u64, String)>> {
-    if message.contains("twitter.com") {
-        Some(download_twitter_video(message).await)
+async fn handle_download_command(message: &str, user_id: i64, chat: JobChat) -> Option<String> {
+    let (kind, url) = if message.contains("twitter.com") {
+        (JobKind::Twitter, message)
     } else if let Some(url) = message.strip_prefix("v ") {
-        Some(download_video(url).await)
+        (JobKind::Video, url.trim())
     } else {
-        None
+        return download_queue::handle_job_command(message, chat);
+    };
+    if let Err(rejection) = download_guard::check_user(user_id).await {
+        return Some(format!("无法下载: {}", rejection));
+    }
+    Some(match download_queue::enqueue(kind, url, user_id, chat) {
+        Ok(job) => format!("已加入下载队列 #{}", job.id),
+        Err(err) => format!("加入下载队列时出错: {:#?}", err),
+    })
+}
+
+pub async fn download(
+    kind: JobKind,
+    url: &str,
+    user_id: i64,
+    on_progress: Option<&OnProgress>,
+) -> Result<(u64, String)> {
+    match kind {
+        JobKind::Video => download_video(url, user_id, on_progress).await,
+        JobKind::Twitter => download_twitter_video(url, user_id, on_progress).await,
     }
 }
 
-async fn download_twitter_video(url: &str) -> Result<(u64, String)> {
+async fn download_twitter_video(
+    url: &str,
+    user_id: i64,
+    on_progress: Option<&OnProgress>,
+) -> Result<(u64, String)> {
     async fn do_request(url: &str) -> Result<String> {
         let result = CLIENT
             .post("https://twdown.net/download.php")
@@ -90,7 +92,7 @@ async fn download_twitter_video(url: &str) -> Result<(u64, String)> {
     let url = find_url_from_response(&response)?
         .ok_or_else(|| anyhow!(format!("failed to find url from response: {}", response)))?;
 
-    let size = download_video(&url)
+    let size = download_video(&url, user_id, on_progress)
         .await
         .context("failed to download the video")?;
     Ok(size)
@@ -123,28 +125,34 @@ fn find_url_from_response(html: &str) -> Result<Option<String>> {
         .map(|(url, _)| url))
 }
 
-async fn download_video(url: &str) -> Result<(u64, String)> {
-    for times in 1..4 {
-        match do_download_video(url).await {
-            Ok(size) => {
-                return Ok(size);
-            }
-            Err(err) => {
-                if times >= 3 {
-                    return Err(err.context("download exceeds maximum retry times"));
-                }
-            }
-        }
-        tokio::time::sleep(Duration::from_secs(3)).await;
-    }
-    return Err(anyhow!("download exceeds maximum retry times"));
-}
-
-async fn do_download_video(url: &str) -> Result<(u64, String)> {
-    let response = CLIENT.get(url).send().await?.error_for_status()?;
-    let file_name = utils::get_file_name(response.url()).unwrap_or(format!("{}.mp4", nanoid!()));
-    let path = format!("{}/{}", cfg::BOT_CONFIG.download_path, file_name);
-    let size = utils::download_file_if_not_exists(response, &path).await?;
+/// Interrupted downloads are retried by `utils::download_file_if_not_exists`.
+async fn download_video(
+    url: &str,
+    user_id: i64,
+    on_progress: Option<&OnProgress>,
+) -> Result<(u64, String)> {
+    let request = CLIENT.get(url);
+    let response = utils::send_cloned(&request).await?;
+    // counts the file into the quotas until it is downloaded
+    let reservation = download_guard::check_response(&response, user_id).await?;
+    let limit = reservation.limit();
+    let fields = NameFields::from_response(&response, "mp4");
+    let claim = naming::claim(&naming::render(&cfg::BOT_CONFIG.naming.video, &fields), url).await?;
+    let size = utils::download_file_if_not_exists(
+        response,
+        request,
+        claim.path(),
+        on_progress,
+        limit.map(|limit| limit.max_bytes()),
+    )
+    .await
+    .map_err(|err| match (limit, err.downcast::<SizeLimitExceeded>()) {
+        (Some(limit), Ok(SizeLimitExceeded(size, _))) => limit.rejection(size).into(),
+        (None, Ok(exceeded)) => exceeded.into(),
+        (_, Err(err)) => err,
+    })?;
+    let path = naming::fix_extension(claim.path(), url).await?;
+    claim.keep();
     Ok((size, path))
 }
 
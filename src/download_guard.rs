@@ -0,0 +1,427 @@
+use crate::cfg::{self, DownloadGuardConfig};
+use crate::download_queue::JobState;
+use crate::storage::STORAGE;
+use lazy_static::lazy_static;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::Response;
+use std::collections::{HashMap, HashSet};
+use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use thiserror::Error;
+
+/// Reasons a download is rejected, which are replied to the user as they are.
+#[derive(Debug, Error, PartialEq)]
+pub enum Rejection {
+    #[error("文件大小 {} 超过上限 {}", human_bytes(*.0), human_bytes(*.1))]
+    TooLarge(u64, u64),
+    #[error("不允许下载类型为 {0} 的文件")]
+    MimeType(String),
+    #[error("下载配额不足，已使用 {} / {}", human_bytes(*.0), human_bytes(*.1))]
+    UserQuota(u64, u64),
+    #[error("下载目录的总配额不足，已使用 {} / {}", human_bytes(*.0), human_bytes(*.1))]
+    TotalQuota(u64, u64),
+    #[error("磁盘剩余空间不足，剩余 {}，需要 {}", human_bytes(*.0), human_bytes(*.1))]
+    DiskSpace(u64, u64),
+    /// The usage or the free space can not be read, downloads are rejected rather than let
+    /// through unchecked.
+    #[error("无法检查下载限制: {0}")]
+    Unavailable(String),
+}
+
+fn human_bytes(bytes: u64) -> String {
+    human_bytes::human_bytes(bytes as f64)
+}
+
+lazy_static! {
+    /// Sizes of the running downloads by their reservations, along with their users.
+    static ref RESERVED: Mutex<HashMap<u64, (i64, u64)>> = Mutex::new(HashMap::new());
+}
+
+static NEXT_RESERVATION: AtomicU64 = AtomicU64::new(0);
+
+/// Counts a running download into the quotas by its Content-Length until it is dropped, as its
+/// file is not complete on the disk yet.
+pub struct Reservation {
+    id: u64,
+    limit: Option<Limit>,
+}
+
+impl Reservation {
+    /// The size the file may grow to while streaming, as Content-Length may be absent or wrong.
+    pub fn limit(&self) -> Option<Limit> {
+        self.limit
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        RESERVED.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// The tightest of the file size limit and the quotas left when a download starts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Limit {
+    File(u64),
+    User { used: u64, quota: u64 },
+    Total { used: u64, quota: u64 },
+}
+
+impl Limit {
+    pub fn max_bytes(&self) -> u64 {
+        match *self {
+            Limit::File(max) => max,
+            Limit::User { used, quota } | Limit::Total { used, quota } => {
+                quota.saturating_sub(used)
+            }
+        }
+    }
+
+    /// The rejection of a download which exceeds the limit at the size.
+    pub fn rejection(&self, size: u64) -> Rejection {
+        match *self {
+            Limit::File(max) => Rejection::TooLarge(size, max),
+            Limit::User { used, quota } => Rejection::UserQuota(used + size, quota),
+            Limit::Total { used, quota } => Rejection::TotalQuota(used + size, quota),
+        }
+    }
+}
+
+/// Checks whether the user may start a download, before it is queued. The quotas are checked
+/// again by `check_response` once the download starts.
+pub async fn check_user(user_id: i64) -> Result<(), Rejection> {
+    tokio::task::spawn_blocking(move || {
+        let config = &cfg::BOT_CONFIG.download_guard;
+        let usage = measure_usage(config, user_id)?;
+        let reserved = RESERVED.lock().unwrap();
+        check_quota(config, usage, &reserved, user_id, 0).map(|_| ())
+    })
+    .await
+    .map_err(|err| Rejection::Unavailable(err.to_string()))?
+}
+
+/// Checks the size and the type of the file in the response before downloading it, and reserves
+/// its size in the quotas. The returned reservation limits the size while streaming.
+pub async fn check_response(response: &Response, user_id: i64) -> Result<Reservation, Rejection> {
+    let config = &cfg::BOT_CONFIG.download_guard;
+    let size = response.content_length();
+    check_size(config, size)?;
+    check_mime_type(
+        config,
+        response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok()),
+    )?;
+    // the usage is read from the disk and the storage off the async workers, and without the
+    // lock held, which is only taken to check and insert the reservation at once
+    tokio::task::spawn_blocking(move || {
+        let config = &cfg::BOT_CONFIG.download_guard;
+        let size = size.unwrap_or(0);
+        let usage = measure_usage(config, user_id)?;
+        check_disk_space(config, size)?;
+        let mut reserved = RESERVED.lock().unwrap();
+        let limit = check_quota(config, usage, &reserved, user_id, size)?;
+        let id = NEXT_RESERVATION.fetch_add(1, Ordering::SeqCst);
+        reserved.insert(id, (user_id, size));
+        Ok(Reservation { id, limit })
+    })
+    .await
+    .map_err(|err| Rejection::Unavailable(err.to_string()))?
+}
+
+fn check_size(config: &DownloadGuardConfig, size: Option<u64>) -> Result<(), Rejection> {
+    match (size, config.max_file_bytes) {
+        (Some(size), Some(max)) if size > max => Err(Rejection::TooLarge(size, max)),
+        _ => Ok(()),
+    }
+}
+
+/// Files without Content-Type are treated as `application/octet-stream`.
+fn check_mime_type(
+    config: &DownloadGuardConfig,
+    content_type: Option<&str>,
+) -> Result<(), Rejection> {
+    if config.allowed_mime_types.is_empty() {
+        return Ok(());
+    }
+    let mime_type = content_type
+        .and_then(|content_type| content_type.split(';').next())
+        .map(|mime_type| mime_type.trim().to_lowercase())
+        .filter(|mime_type| !mime_type.is_empty())
+        .unwrap_or_else(|| "application/octet-stream".to_string());
+    let allowed = config.allowed_mime_types.iter().any(|allowed| {
+        let allowed = allowed.to_lowercase();
+        match allowed.strip_suffix("/*") {
+            Some(prefix) => mime_type.split('/').next() == Some(prefix),
+            None => allowed == mime_type,
+        }
+    });
+    if allowed {
+        Ok(())
+    } else {
+        Err(Rejection::MimeType(mime_type))
+    }
+}
+
+/// Sizes of the files downloaded by the user and of `download_path`, or None without quotas.
+fn measure_usage(
+    config: &DownloadGuardConfig,
+    user_id: i64,
+) -> Result<Option<(u64, u64)>, Rejection> {
+    if config.user_quota_bytes.is_none() && config.total_quota_bytes.is_none() {
+        return Ok(None);
+    }
+    let jobs = STORAGE
+        .downloads
+        .values()
+        .map_err(|err| Rejection::Unavailable(format!("{:#}", err)))?;
+    let user_used = files_size(
+        jobs.iter()
+            .filter(|job| job.user_id == user_id && job.state == JobState::Done)
+            .filter_map(|job| job.path.as_deref()),
+    );
+    let total_used = dir_size(Path::new(&cfg::BOT_CONFIG.download_path))
+        .map_err(|err| Rejection::Unavailable(err.to_string()))?;
+    Ok(Some((user_used, total_used)))
+}
+
+/// Adds the reservations of the running downloads to the usage, and checks it along with the
+/// size of the new download. Returns the limit of the new download while streaming.
+fn check_quota(
+    config: &DownloadGuardConfig,
+    usage: Option<(u64, u64)>,
+    reserved: &HashMap<u64, (i64, u64)>,
+    user_id: i64,
+    size: u64,
+) -> Result<Option<Limit>, Rejection> {
+    let (user_used, total_used) = match usage {
+        Some(usage) => usage,
+        None => return Ok(limit(config, 0, 0)),
+    };
+    let (user_used, total_used) = reserved.values().fold(
+        (user_used, total_used),
+        |(user_used, total_used), (user, size)| {
+            if *user == user_id {
+                (user_used + size, total_used + size)
+            } else {
+                (user_used, total_used + size)
+            }
+        },
+    );
+    check_usage(config, user_used, total_used, size)?;
+    Ok(limit(config, user_used, total_used))
+}
+
+fn limit(config: &DownloadGuardConfig, user_used: u64, total_used: u64) -> Option<Limit> {
+    [
+        config.max_file_bytes.map(Limit::File),
+        config.user_quota_bytes.map(|quota| Limit::User {
+            used: user_used,
+            quota,
+        }),
+        config.total_quota_bytes.map(|quota| Limit::Total {
+            used: total_used,
+            quota,
+        }),
+    ]
+    .into_iter()
+    .flatten()
+    .min_by_key(|limit| limit.max_bytes())
+}
+
+/// Returns the total size of the files which still exist, counting each path once, e.g. of a
+/// url downloaded twice.
+fn files_size<'a>(paths: impl Iterator<Item = &'a str>) -> u64 {
+    paths
+        .collect::<HashSet<&str>>()
+        .into_iter()
+        .filter_map(|path| std::fs::metadata(path).ok())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+/// Returns the total size of the files under the directory, including the images downloaded
+/// by the searchers. Partial downloads are left out, as their sizes are reserved in full.
+fn dir_size(dir: &Path) -> std::io::Result<u64> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        // not created before the first download, or removed since it is listed
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err),
+    };
+    let mut size = 0;
+    for entry in entries {
+        let entry = entry?;
+        let path = entry.path();
+        size += match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => dir_size(&path)?,
+            Ok(_)
+                if path
+                    .extension()
+                    .is_some_and(|extension| extension == "part") =>
+            {
+                0
+            }
+            Ok(metadata) => metadata.len(),
+            Err(err) if err.kind() == ErrorKind::NotFound => 0,
+            Err(err) => return Err(err),
+        };
+    }
+    Ok(size)
+}
+
+fn check_usage(
+    config: &DownloadGuardConfig,
+    user_used: u64,
+    total_used: u64,
+    size: u64,
+) -> Result<(), Rejection> {
+    if let Some(quota) = config.user_quota_bytes {
+        if user_used >= quota || user_used + size > quota {
+            return Err(Rejection::UserQuota(user_used, quota));
+        }
+    }
+    if let Some(quota) = config.total_quota_bytes {
+        if total_used >= quota || total_used + size > quota {
+            return Err(Rejection::TotalQuota(total_used, quota));
+        }
+    }
+    Ok(())
+}
+
+fn check_disk_space(config: &DownloadGuardConfig, size: u64) -> Result<(), Rejection> {
+    let available = fs2::available_space(&cfg::BOT_CONFIG.download_path)
+        .map_err(|err| Rejection::Unavailable(err.to_string()))?;
+    let required = size + config.min_free_bytes;
+    if available < required {
+        return Err(Rejection::DiskSpace(available, required));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cfg::DownloadGuardConfig;
+    use crate::download_guard::{
+        check_mime_type, check_size, check_usage, dir_size, files_size, limit, Limit, Rejection,
+    };
+
+    #[test]
+    fn check_size_test() {
+        let config = DownloadGuardConfig {
+            max_file_bytes: Some(100),
+            ..Default::default()
+        };
+        assert_eq!(check_size(&config, Some(100)), Ok(()));
+        assert_eq!(check_size(&config, None), Ok(()));
+        assert_eq!(
+            check_size(&config, Some(101)),
+            Err(Rejection::TooLarge(101, 100))
+        );
+        assert_eq!(
+            Rejection::TooLarge(2048, 1024).to_string(),
+            "文件大小 2 KB 超过上限 1 KB"
+        );
+    }
+
+    #[test]
+    fn check_mime_type_test() {
+        let config = DownloadGuardConfig::default();
+        assert_eq!(check_mime_type(&config, Some("video/mp4")), Ok(()));
+        assert_eq!(
+            check_mime_type(&config, Some("Video/WebM; codecs=vp9")),
+            Ok(())
+        );
+        assert_eq!(
+            check_mime_type(&config, None),
+            Err(Rejection::MimeType("application/octet-stream".to_string()))
+        );
+        assert_eq!(
+            check_mime_type(&config, Some("text/html; charset=utf-8")),
+            Err(Rejection::MimeType("text/html".to_string()))
+        );
+
+        let config = DownloadGuardConfig {
+            allowed_mime_types: vec![],
+            ..Default::default()
+        };
+        assert_eq!(check_mime_type(&config, Some("text/html")), Ok(()));
+    }
+
+    #[test]
+    fn check_usage_test() {
+        let config = DownloadGuardConfig {
+            user_quota_bytes: Some(40),
+            total_quota_bytes: Some(100),
+            ..Default::default()
+        };
+        assert_eq!(check_usage(&config, 30, 80, 10), Ok(()));
+        assert_eq!(
+            check_usage(&config, 30, 80, 11),
+            Err(Rejection::UserQuota(30, 40))
+        );
+        assert_eq!(
+            check_usage(&config, 40, 80, 0),
+            Err(Rejection::UserQuota(40, 40))
+        );
+        assert_eq!(
+            check_usage(&config, 0, 95, 10),
+            Err(Rejection::TotalQuota(95, 100))
+        );
+    }
+
+    #[test]
+    fn limit_test() {
+        let config = DownloadGuardConfig {
+            max_file_bytes: Some(50),
+            user_quota_bytes: Some(40),
+            total_quota_bytes: Some(100),
+            ..Default::default()
+        };
+        assert_eq!(
+            limit(&config, 0, 0),
+            Some(Limit::User { used: 0, quota: 40 })
+        );
+        let total = limit(&config, 0, 80).unwrap();
+        assert_eq!(
+            total,
+            Limit::Total {
+                used: 80,
+                quota: 100
+            }
+        );
+        assert_eq!(total.max_bytes(), 20);
+        assert_eq!(total.rejection(30), Rejection::TotalQuota(110, 100));
+        assert_eq!(
+            limit(&DownloadGuardConfig::default(), 0, 0),
+            DownloadGuardConfig::default()
+                .max_file_bytes
+                .map(Limit::File)
+        );
+    }
+
+    #[test]
+    fn usage_test() {
+        let dir = std::env::temp_dir().join(format!("download_guard_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("nested")).unwrap();
+        std::fs::write(dir.join("a.mp4"), [0; 30]).unwrap();
+        std::fs::write(dir.join("nested/b.jpg"), [0; 50]).unwrap();
+        std::fs::write(dir.join("nested/c.mp4.part"), [0; 1000]).unwrap();
+        assert_eq!(dir_size(&dir).unwrap(), 80);
+        assert_eq!(dir_size(&dir.join("missing")).unwrap(), 0);
+
+        let a = dir.join("a.mp4");
+        let a = a.to_str().unwrap();
+        let missing = dir.join("missing.mp4");
+        // the same file is counted once, and removed files are not counted
+        assert_eq!(
+            files_size([a, a, missing.to_str().unwrap()].into_iter()),
+            30
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
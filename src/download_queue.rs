@@ -0,0 +1,519 @@
+use crate::download;
+use crate::download_guard::Rejection;
+use crate::message::*;
+use crate::storage::STORAGE;
+use crate::{cfg, local_search, sender, utils};
+use anyhow::{bail, Result};
+use lazy_static::lazy_static;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use tokio::task::AbortHandle;
+
+lazy_static! {
+    static ref PERMITS: Semaphore = Semaphore::new(cfg::BOT_CONFIG.download_concurrency);
+    /// Tasks of the queued and running jobs.
+    static ref TASKS: Mutex<HashMap<u64, AbortHandle>> = Mutex::new(HashMap::new());
+    /// Progress of the running jobs.
+    static ref PROGRESS: Mutex<HashMap<u64, Progress>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobKind {
+    Video,
+    /// A tweet whose video is resolved by twdown.net.
+    Twitter,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    Running,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+impl JobState {
+    fn label(&self) -> &'static str {
+        match self {
+            JobState::Queued => "排队中",
+            JobState::Running => "下载中",
+            JobState::Done => "已完成",
+            JobState::Failed => "失败",
+            JobState::Cancelled => "已取消",
+        }
+    }
+}
+
+/// The chat a job is requested from, where its result is reported.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum JobChat {
+    Group { group_id: i64 },
+    Private { user_id: i64 },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct DownloadJob {
+    pub id: u64,
+    pub kind: JobKind,
+    pub url: String,
+    pub user_id: i64,
+    pub chat: JobChat,
+    pub state: JobState,
+    pub path: Option<String>,
+    pub size: Option<u64>,
+    pub error: Option<String>,
+    pub time: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Progress {
+    received: u64,
+    total: Option<u64>,
+    /// When the speed is measured from, and the bytes received by then, e.g. of a resumed download.
+    started: Instant,
+    initial: u64,
+    reported: Instant,
+}
+
+impl Progress {
+    fn new(received: u64, now: Instant) -> Self {
+        Progress {
+            received,
+            total: None,
+            started: now,
+            initial: received,
+            reported: now,
+        }
+    }
+
+    fn update(&mut self, received: u64, total: Option<u64>, now: Instant) {
+        // the download is restarted
+        if received < self.received {
+            self.started = now;
+            self.initial = received;
+        }
+        self.received = received;
+        self.total = total;
+    }
+
+    /// Bytes received per second.
+    fn speed(&self, now: Instant) -> f64 {
+        let secs = now.duration_since(self.started).as_secs_f64();
+        if secs <= 0.0 {
+            return 0.0;
+        }
+        (self.received - self.initial) as f64 / secs
+    }
+
+    fn format(&self, id: u64, now: Instant) -> String {
+        let speed = self.speed(now);
+        let received = human_bytes::human_bytes(self.received as f64);
+        let speed_text = format!("{}/s", human_bytes::human_bytes(speed));
+        match self.total {
+            Some(total) if total > 0 => {
+                let eta = if speed > 0.0 {
+                    format_duration(
+                        (total.saturating_sub(self.received) as f64 / speed).ceil() as u64
+                    )
+                } else {
+                    "未知".to_string()
+                };
+                format!(
+                    "下载任务 #{} 进度: {:.1}% ({}/{})，速度: {}，剩余时间: {}",
+                    id,
+                    self.received as f64 * 100.0 / total as f64,
+                    received,
+                    human_bytes::human_bytes(total as f64),
+                    speed_text,
+                    eta
+                )
+            }
+            _ => format!(
+                "下载任务 #{} 已下载: {}，速度: {}",
+                id, received, speed_text
+            ),
+        }
+    }
+}
+
+fn format_duration(secs: u64) -> String {
+    match (secs / 3600, secs % 3600 / 60, secs % 60) {
+        (0, 0, s) => format!("{}秒", s),
+        (0, m, s) => format!("{}分{}秒", m, s),
+        (h, m, _) => format!("{}小时{}分", h, m),
+    }
+}
+
+/// Downloads recorded before the queue was added.
+#[derive(Debug, Deserialize)]
+struct LegacyDownloadRecord {
+    url: String,
+    path: String,
+    size: u64,
+    user_id: i64,
+    time: u64,
+}
+
+/// Converts the records of finished downloads into done jobs.
+pub fn migrate_legacy_records(tree: &sled::Tree) -> Result<usize> {
+    let mut migrated = 0;
+    for item in tree.iter() {
+        let (key, value) = item?;
+        let record: LegacyDownloadRecord = match serde_json::from_slice(&value) {
+            Ok(record) => record,
+            Err(_) => continue,
+        };
+        let id = u64::from_be_bytes(key.as_ref().try_into()?);
+        let job = DownloadJob {
+            id,
+            kind: JobKind::Video,
+            url: record.url,
+            user_id: record.user_id,
+            chat: JobChat::Private {
+                user_id: record.user_id,
+            },
+            state: JobState::Done,
+            path: Some(record.path),
+            size: Some(record.size),
+            error: None,
+            time: record.time,
+        };
+        tree.insert(key, serde_json::to_vec(&job)?)?;
+        migrated += 1;
+    }
+    Ok(migrated)
+}
+
+pub fn enqueue(kind: JobKind, url: &str, user_id: i64, chat: JobChat) -> Result<DownloadJob> {
+    let job = DownloadJob {
+        id: STORAGE.generate_id()?,
+        kind,
+        url: url.to_string(),
+        user_id,
+        chat,
+        state: JobState::Queued,
+        path: None,
+        size: None,
+        error: None,
+        time: utils::unix_timestamp(),
+    };
+    STORAGE.downloads.insert(&job.id, &job)?;
+    spawn(job.id);
+    Ok(job)
+}
+
+/// Queues the jobs which were queued or running when the bot stopped.
+pub fn resume() {
+    let jobs = match STORAGE.downloads.values() {
+        Ok(jobs) => jobs,
+        Err(err) => {
+            error!("failed to load download jobs: {:#?}", err);
+            return;
+        }
+    };
+    for mut job in jobs {
+        if job.state != JobState::Queued && job.state != JobState::Running {
+            continue;
+        }
+        job.state = JobState::Queued;
+        if let Err(err) = STORAGE.downloads.insert(&job.id, &job) {
+            error!("failed to resume download job {}: {:#?}", job.id, err);
+            continue;
+        }
+        info!("resuming download job {}", job.id);
+        spawn(job.id);
+    }
+}
+
+fn spawn(id: u64) {
+    // held until the handle is inserted, in case the task finishes first
+    let mut tasks = TASKS.lock().unwrap();
+    let task = tokio::spawn(async move {
+        let permit = PERMITS.acquire().await;
+        if let Err(err) = run(id).await {
+            error!("failed to run download job {}: {:#?}", id, err);
+        }
+        drop(permit);
+        TASKS.lock().unwrap().remove(&id);
+    });
+    tasks.insert(id, task.abort_handle());
+}
+
+async fn run(id: u64) -> Result<()> {
+    let mut job = match STORAGE.downloads.get(&id)? {
+        Some(job) if job.state == JobState::Queued => job,
+        _ => return Ok(()),
+    };
+    job.state = JobState::Running;
+    STORAGE.downloads.insert(&id, &job)?;
+
+    let chat = job.chat;
+    let on_progress = move |received: u64, total: Option<u64>| track(id, chat, received, total);
+    let result = download::download(job.kind, &job.url, job.user_id, Some(&on_progress)).await;
+    PROGRESS.lock().unwrap().remove(&id);
+    match &result {
+        Ok((size, path)) => {
+            job.state = JobState::Done;
+            job.size = Some(*size);
+            job.path = Some(path.clone());
+            local_search::spawn_index_file(path.clone());
+        }
+        Err(err) => {
+            job.state = JobState::Failed;
+            job.error = Some(match err.downcast_ref::<Rejection>() {
+                Some(rejection) => rejection.to_string(),
+                None => format!("{:#?}", err),
+            });
+        }
+    }
+    // the job may be cancelled after the download is finished, where the task is not aborted
+    match STORAGE.downloads.get(&id)? {
+        Some(current) if current.state == JobState::Running => {}
+        _ => return Ok(()),
+    }
+    STORAGE.downloads.insert(&id, &job)?;
+    if let Some(action) = report(&job) {
+        sender::send(action).await;
+    }
+    Ok(())
+}
+
+/// Records the progress of a job, and reports it at most once per the configured interval.
+fn track(id: u64, chat: JobChat, received: u64, total: Option<u64>) {
+    let now = Instant::now();
+    let mut jobs = PROGRESS.lock().unwrap();
+    let progress = jobs
+        .entry(id)
+        .or_insert_with(|| Progress::new(received, now));
+    progress.update(received, total, now);
+
+    let interval = cfg::BOT_CONFIG.download_progress_interval_secs;
+    if interval == 0 || now.duration_since(progress.reported) < Duration::from_secs(interval) {
+        return;
+    }
+    progress.reported = now;
+    let action = chat_message(chat, progress.format(id, now));
+    tokio::spawn(sender::send(action));
+}
+
+fn chat_message(chat: JobChat, message: String) -> BotResponseAction {
+    match chat {
+        JobChat::Group { group_id } => BotResponseAction::GroupMessage { group_id, message },
+        JobChat::Private { user_id } => BotResponseAction::PrivateMessage { user_id, message },
+    }
+}
+
+fn report(job: &DownloadJob) -> Option<BotResponseAction> {
+    let message = match (job.state, &job.path, job.chat) {
+        (JobState::Done, Some(path), JobChat::Group { group_id }) => {
+            return Some(BotResponseAction::GroupFile {
+                group_id,
+                file: path.clone(),
+                name: Path::new(path).file_name()?.to_str()?.to_string(),
+            });
+        }
+        (JobState::Done, _, _) => format!(
+            "下载任务 #{} 视频保存成功，大小: {}",
+            job.id,
+            human_bytes::human_bytes(job.size.unwrap_or(0) as f64)
+        ),
+        (JobState::Failed, _, _) => format!(
+            "下载任务 #{} 保存视频时出错: {}",
+            job.id,
+            job.error.as_deref().unwrap_or_default()
+        ),
+        _ => return None,
+    };
+    Some(chat_message(job.chat, message))
+}
+
+fn cancel(id: u64) -> Result<DownloadJob> {
+    let mut job = match STORAGE.downloads.get(&id)? {
+        Some(job) => job,
+        None => bail!("下载任务 #{} 不存在", id),
+    };
+    if job.state != JobState::Queued && job.state != JobState::Running {
+        bail!("下载任务 #{} {}，无法取消", id, job.state.label());
+    }
+    if let Some(task) = TASKS.lock().unwrap().remove(&id) {
+        task.abort();
+    }
+    PROGRESS.lock().unwrap().remove(&id);
+    job.state = JobState::Cancelled;
+    STORAGE.downloads.insert(&id, &job)?;
+    Ok(job)
+}
+
+fn retry(id: u64) -> Result<DownloadJob> {
+    let mut job = match STORAGE.downloads.get(&id)? {
+        Some(job) => job,
+        None => bail!("下载任务 #{} 不存在", id),
+    };
+    if job.state != JobState::Failed && job.state != JobState::Cancelled {
+        bail!("下载任务 #{} {}，无法重试", id, job.state.label());
+    }
+    job.state = JobState::Queued;
+    job.error = None;
+    STORAGE.downloads.insert(&id, &job)?;
+    spawn(id);
+    Ok(job)
+}
+
+fn progress(id: u64) -> Result<String> {
+    if let Some(progress) = PROGRESS.lock().unwrap().get(&id) {
+        return Ok(progress.format(id, Instant::now()));
+    }
+    match STORAGE.downloads.get(&id)? {
+        Some(job) => Ok(describe(&job)),
+        None => bail!("下载任务 #{} 不存在", id),
+    }
+}
+
+fn describe(job: &DownloadJob) -> String {
+    format!("下载任务 #{} {}", job.id, job.state.label())
+}
+
+/// Handles `jobs`, `job-cancel <id>`, `job-retry <id>` and `job-progress <id>`.
+pub fn handle_job_command(message: &str, chat: JobChat) -> Option<String> {
+    if message == "jobs" {
+        return Some(match STORAGE.downloads.values() {
+            Ok(jobs) => format_jobs(&jobs, chat, 10),
+            Err(err) => format!("获取下载任务时出错: {:#?}", err),
+        });
+    }
+
+    let (command, id) = message.split_once(' ')?;
+    let action: fn(u64) -> Result<String> = match command {
+        "job-cancel" => |id| cancel(id).map(|job| describe(&job)),
+        "job-retry" => |id| retry(id).map(|job| describe(&job)),
+        "job-progress" => progress,
+        _ => return None,
+    };
+    let id = match id.trim().trim_start_matches('#').parse::<u64>() {
+        Ok(id) => id,
+        Err(_) => return Some(format!("用法: {} <id>", command)),
+    };
+    Some(action(id).unwrap_or_else(|err| err.to_string()))
+}
+
+/// Lists the latest jobs requested from the chat, the ids are generated in ascending order.
+fn format_jobs(jobs: &[DownloadJob], chat: JobChat, limit: usize) -> String {
+    let jobs = jobs
+        .iter()
+        .filter(|job| job.chat == chat)
+        .collect::<Vec<&DownloadJob>>();
+    if jobs.is_empty() {
+        return "没有下载任务".to_string();
+    }
+    jobs.iter()
+        .rev()
+        .take(limit)
+        .map(|job| format!("#{} [{}] {}", job.id, job.state.label(), job.url))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::download_queue::{
+        format_jobs, migrate_legacy_records, DownloadJob, JobChat, JobKind, JobState, Progress,
+    };
+    use std::time::{Duration, Instant};
+
+    fn job(id: u64, state: JobState) -> DownloadJob {
+        DownloadJob {
+            id,
+            kind: JobKind::Video,
+            url: format!("https://example.com/{}.mp4", id),
+            user_id: 10000,
+            chat: JobChat::Group { group_id: 1 },
+            state,
+            path: None,
+            size: None,
+            error: None,
+            time: 0,
+        }
+    }
+
+    #[test]
+    fn format_jobs_test() {
+        let chat = JobChat::Group { group_id: 1 };
+        assert_eq!(format_jobs(&[], chat, 2), "没有下载任务");
+        let other = DownloadJob {
+            chat: JobChat::Private { user_id: 10000 },
+            ..job(4, JobState::Queued)
+        };
+        let jobs = [
+            job(1, JobState::Done),
+            job(2, JobState::Failed),
+            job(3, JobState::Running),
+            other,
+        ];
+        assert_eq!(
+            format_jobs(&jobs, chat, 2),
+            "#3 [下载中] https://example.com/3.mp4\n#2 [失败] https://example.com/2.mp4"
+        );
+        assert_eq!(
+            format_jobs(&jobs, JobChat::Group { group_id: 2 }, 2),
+            "没有下载任务"
+        );
+    }
+
+    #[test]
+    fn format_progress_test() {
+        let now = Instant::now();
+        let mut progress = Progress::new(1024, now);
+        progress.update(
+            1024 + 20 * 1024 * 1024,
+            Some(100 * 1024 * 1024),
+            now + Duration::from_secs(10),
+        );
+        assert_eq!(
+            progress.format(1, now + Duration::from_secs(10)),
+            "下载任务 #1 进度: 20.0% (20 MB/100 MB)，速度: 2 MB/s，剩余时间: 40秒"
+        );
+
+        progress.update(2048, None, now + Duration::from_secs(20));
+        assert_eq!(progress.initial, 2048);
+        assert_eq!(
+            progress.format(1, now + Duration::from_secs(20)),
+            "下载任务 #1 已下载: 2 KB，速度: 0 B/s"
+        );
+    }
+
+    #[test]
+    fn migrate_legacy_records_test() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = db.open_tree("downloads").unwrap();
+        tree.insert(
+            7u64.to_be_bytes(),
+            r#"{"url":"https://example.com/7.mp4","path":"/tmp/7.mp4","size":42,"user_id":10000,"time":100}"#,
+        )
+        .unwrap();
+        tree.insert(
+            8u64.to_be_bytes(),
+            serde_json::to_vec(&job(8, JobState::Queued)).unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(migrate_legacy_records(&tree).unwrap(), 1);
+        let migrated: DownloadJob =
+            serde_json::from_slice(&tree.get(7u64.to_be_bytes()).unwrap().unwrap()).unwrap();
+        assert_eq!(migrated.id, 7);
+        assert_eq!(migrated.state, JobState::Done);
+        assert_eq!(migrated.path.as_deref(), Some("/tmp/7.mp4"));
+        assert_eq!(migrated.chat, JobChat::Private { user_id: 10000 });
+        let untouched: DownloadJob =
+            serde_json::from_slice(&tree.get(8u64.to_be_bytes()).unwrap().unwrap()).unwrap();
+        assert_eq!(untouched, job(8, JobState::Queued));
+    }
+}
@@ -0,0 +1,176 @@
+use crate::cfg;
+use crate::client::CLIENT;
+use crate::searcher::*;
+use anyhow::anyhow;
+use async_trait::async_trait;
+use reqwest::multipart::Form;
+use std::collections::HashMap;
+use visdom::Vis;
+
+pub struct EHentaiImageSearcher {
+    pub config: cfg::SearcherConfig,
+    /// Cookies of a logged-in account, searches ExHentai instead of E-Hentai when present.
+    pub exhentai_cookie: Option<String>,
+}
+
+/// A file search hit names the gallery rather than the page, so it ranks below direct matches.
+const EHENTAI_SIMILARITY: f64 = 0.6;
+
+#[async_trait]
+impl ImageSearcher for EHentaiImageSearcher {
+    fn get_name(&self) -> &'static str {
+        "e-hentai"
+    }
+
+    fn get_config(&self) -> &cfg::SearcherConfig {
+        &self.config
+    }
+
+    async fn search(&self, url: &str) -> ImageSearchResult {
+        let image = CLIENT
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        self.search_bytes(&image).await
+    }
+
+    async fn search_bytes(&self, image: &[u8]) -> ImageSearchResult {
+        let form = Form::new()
+            .part("sfile", image_part(image))
+            .text("f_sfile", "File Search")
+            .text("fs_similar", "on")
+            .text("fs_covers", "on");
+
+        let request = match &self.exhentai_cookie {
+            Some(cookie) => CLIENT
+                .post("https://upld.exhentai.org/upld/image_lookup.php")
+                .header(reqwest::header::COOKIE, cookie),
+            None => CLIENT.post("https://upld.e-hentai.org/image_lookup.php"),
+        };
+        let html = request
+            .multipart(form)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        self.parse_result(html.as_str())
+    }
+}
+
+impl EHentaiImageSearcher {
+    fn parse_result(&self, html: &str) -> ImageSearchResult {
+        let root = Vis::load(html).map_err(|e| anyhow!(e))?;
+        let rows = root.find("table.itg tr");
+        let mut images = vec![];
+        for i in 0..rows.length() {
+            let row = rows.eq(i);
+            let link = row.find(".gl3c a");
+            let url = match link.attr("href") {
+                Some(href) => href.to_string(),
+                None => continue,
+            };
+
+            let mut metadata: HashMap<String, String> = HashMap::new();
+            let title = row.find(".glink").text();
+            if !title.is_empty() {
+                metadata.insert("标题".to_string(), title);
+            }
+            let category = row.find(".gl1c .cn").text();
+            if !category.is_empty() {
+                metadata.insert("分类".to_string(), category);
+            }
+            let tags = row
+                .find(".gt")
+                .map(|_, tag| tag.get_attribute("title").map(|tag| tag.to_string()))
+                .into_iter()
+                .flatten()
+                .collect::<Vec<String>>();
+            if !tags.is_empty() {
+                metadata.insert("标签".to_string(), tags.join(", "));
+            }
+
+            images.push(SourceImage {
+                url,
+                searcher: self.get_name(),
+                similarity: EHENTAI_SIMILARITY,
+                low_confidence: false,
+                metadata,
+                preview: None,
+            });
+        }
+        Ok(images)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cfg::SearcherConfig;
+    use crate::ehentai::EHentaiImageSearcher;
+
+    #[test]
+    fn parse_ehentai_response() {
+        let searcher = EHentaiImageSearcher {
+            config: SearcherConfig::ehentai(),
+            exhentai_cookie: None,
+        };
+        let images = searcher
+            .parse_result(
+                r###"<!DOCTYPE html>
+<html>
+<head><title>E-Hentai Galleries</title></head>
+<body>
+<div class="ido">
+<div class="searchtext"><p>Showing 2 results</p></div>
+<table class="itg gltc">
+<tr><th>Category</th><th>Published</th><th>Title</th><th>Uploader</th></tr>
+<tr>
+<td class="gl1c glcat"><div class="cn ct2" onclick="document.location='https://e-hentai.org/doujinshi'">Doujinshi</div></td>
+<td class="gl2c"><div class="glthumb"></div><div><div id="posted_2345678">2022-09-30 12:34</div></div></td>
+<td class="gl3c glname"><a href="https://e-hentai.org/g/2345678/0123456789/"><div class="glink">(C100) [Circle (Artist)] Title (Original)</div><div><div class="gt" title="parody:original">original</div><div class="gt" title="female:stockings">stockings</div></div></a></td>
+<td class="gl4c glhide"><div><a href="https://e-hentai.org/uploader/someone">someone</a></div><div>24 pages</div></td>
+</tr>
+<tr>
+<td class="gl1c glcat"><div class="cn ct9">Non-H</div></td>
+<td class="gl2c"><div><div id="posted_2345679">2022-10-01 08:00</div></div></td>
+<td class="gl3c glname"><a href="https://e-hentai.org/g/2345679/abcdef0123/"><div class="glink">[Artist] Artworks 2022</div></a></td>
+<td class="gl4c glhide"><div><a href="https://e-hentai.org/uploader/other">other</a></div><div>120 pages</div></td>
+</tr>
+</table>
+</div>
+</body>
+</html>"###,
+            )
+            .unwrap();
+        assert_eq!(images.len(), 2);
+        assert_eq!(images[0].url, "https://e-hentai.org/g/2345678/0123456789/");
+        assert_eq!(
+            images[0].metadata["标题"],
+            "(C100) [Circle (Artist)] Title (Original)"
+        );
+        assert_eq!(images[0].metadata["分类"], "Doujinshi");
+        assert_eq!(
+            images[0].metadata["标签"],
+            "parody:original, female:stockings"
+        );
+        assert_eq!(images[1].metadata["分类"], "Non-H");
+        assert!(!images[1].metadata.contains_key("标签"));
+    }
+
+    #[test]
+    fn parse_ehentai_empty_response() {
+        let searcher = EHentaiImageSearcher {
+            config: SearcherConfig::ehentai(),
+            exhentai_cookie: None,
+        };
+        let images = searcher
+            .parse_result(
+                r###"<html><body><div class="ido"><p>No hits found</p></div></body></html>"###,
+            )
+            .unwrap();
+        assert!(images.is_empty());
+    }
+}
@@ -0,0 +1,291 @@
+use crate::cfg;
+use crate::message::*;
+use crate::searcher::*;
+use async_trait::async_trait;
+use log::{debug, warn};
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum SearcherError {
+    #[error("{0} is skipped until its circuit breaker closes")]
+    CircuitOpen(&'static str),
+    #[error("{0} timed out after {1:?}")]
+    Timeout(&'static str, Duration),
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct SearcherStats {
+    pub successes: u64,
+    pub failures: u64,
+    pub timeouts: u64,
+    pub skipped: u64,
+    pub total_latency: Duration,
+}
+
+#[derive(Debug, Default)]
+struct CircuitBreaker {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+}
+
+/// Wraps an `ImageSearcher` with a timeout, a circuit breaker and statistics.
+pub struct GuardedSearcher {
+    inner: Box<dyn ImageSearcher + Send + Sync>,
+    failure_threshold: u32,
+    cooldown: Duration,
+    breaker: Mutex<CircuitBreaker>,
+    stats: Mutex<SearcherStats>,
+}
+
+impl GuardedSearcher {
+    pub fn new(
+        inner: Box<dyn ImageSearcher + Send + Sync>,
+        failure_threshold: u32,
+        cooldown: Duration,
+    ) -> Self {
+        GuardedSearcher {
+            inner,
+            failure_threshold,
+            cooldown,
+            breaker: Mutex::new(CircuitBreaker::default()),
+            stats: Mutex::new(SearcherStats::default()),
+        }
+    }
+
+    pub fn get_stats(&self) -> SearcherStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    pub fn is_open(&self) -> bool {
+        matches!(self.breaker.lock().unwrap().open_until, Some(until) if until > Instant::now())
+    }
+
+    fn record_success(&self, latency: Duration) {
+        *self.breaker.lock().unwrap() = CircuitBreaker::default();
+        let mut stats = self.stats.lock().unwrap();
+        stats.successes += 1;
+        stats.total_latency += latency;
+    }
+
+    fn record_failure(&self, latency: Duration, timeout: bool) {
+        {
+            let mut stats = self.stats.lock().unwrap();
+            if timeout {
+                stats.timeouts += 1;
+            } else {
+                stats.failures += 1;
+            }
+            stats.total_latency += latency;
+        }
+
+        let mut breaker = self.breaker.lock().unwrap();
+        breaker.consecutive_failures += 1;
+        if breaker.consecutive_failures >= self.failure_threshold {
+            warn!(
+                "{} failed {} times in a row, skipping it for {:?}",
+                self.get_name(),
+                breaker.consecutive_failures,
+                self.cooldown
+            );
+            breaker.open_until = Some(Instant::now() + self.cooldown);
+        }
+    }
+}
+
+#[async_trait]
+impl ImageSearcher for GuardedSearcher {
+    fn get_name(&self) -> &'static str {
+        self.inner.get_name()
+    }
+
+    fn get_config(&self) -> &cfg::SearcherConfig {
+        self.inner.get_config()
+    }
+
+    async fn search(&self, url: &str) -> ImageSearchResult {
+        self.guard(self.inner.search(url)).await
+    }
+
+    async fn search_bytes(&self, image: &[u8]) -> ImageSearchResult {
+        self.guard(self.inner.search_bytes(image)).await
+    }
+}
+
+impl GuardedSearcher {
+    async fn guard(&self, search: impl Future<Output = ImageSearchResult>) -> ImageSearchResult {
+        if self.is_open() {
+            debug!("circuit breaker of {} is open", self.get_name());
+            self.stats.lock().unwrap().skipped += 1;
+            return Err(SearcherError::CircuitOpen(self.get_name()).into());
+        }
+
+        let timeout = Duration::from_secs(self.get_config().timeout_secs);
+        let start = Instant::now();
+        match tokio::time::timeout(timeout, search).await {
+            Ok(Ok(images)) => {
+                self.record_success(start.elapsed());
+                Ok(images)
+            }
+            Ok(Err(err)) => {
+                self.record_failure(start.elapsed(), false);
+                Err(err)
+            }
+            Err(_) => {
+                self.record_failure(start.elapsed(), true);
+                Err(SearcherError::Timeout(self.get_name(), timeout).into())
+            }
+        }
+    }
+}
+
+pub async fn on_private_message(message: OneBotPrivateMessage) -> Option<BotResponseAction> {
+    let OneBotPrivateMessage {
+        user_id, message, ..
+    } = message;
+    if user_id != cfg::BOT_CONFIG.admin_user_id || message.trim() != "searcher-stats" {
+        return None;
+    }
+
+    Some(BotResponseAction::PrivateMessage {
+        user_id,
+        message: format_stats(&SEARCHERS),
+    })
+}
+
+pub async fn on_group_message(message: OneBotGroupMessage) -> Option<BotResponseAction> {
+    let OneBotGroupMessage {
+        message,
+        user_id,
+        group_id,
+        ..
+    } = message;
+    if user_id != cfg::BOT_CONFIG.admin_user_id || message.trim() != "searcher-stats" {
+        return None;
+    }
+
+    Some(BotResponseAction::GroupMessage {
+        group_id,
+        message: format_stats(&SEARCHERS),
+    })
+}
+
+fn format_stats(searchers: &[GuardedSearcher]) -> String {
+    searchers
+        .iter()
+        .map(|searcher| {
+            let stats = searcher.get_stats();
+            let completed = stats.successes + stats.failures + stats.timeouts;
+            let average_latency = if completed > 0 {
+                stats.total_latency / u32::try_from(completed).unwrap_or(u32::MAX)
+            } else {
+                Duration::ZERO
+            };
+            format!(
+                "{}{}\n成功 {} / 失败 {} / 超时 {} / 跳过 {}\n平均耗时 {:.2}s",
+                searcher.get_name(),
+                if searcher.is_open() {
+                    "（已熔断）"
+                } else {
+                    ""
+                },
+                stats.successes,
+                stats.failures,
+                stats.timeouts,
+                stats.skipped,
+                average_latency.as_secs_f64()
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cfg::SearcherConfig;
+    use crate::health::{GuardedSearcher, SearcherError};
+    use crate::searcher::{ImageSearchResult, ImageSearcher};
+    use anyhow::anyhow;
+    use async_trait::async_trait;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    struct FailingSearcher {
+        config: SearcherConfig,
+        calls: Arc<AtomicU32>,
+        delay: Duration,
+    }
+
+    #[async_trait]
+    impl ImageSearcher for FailingSearcher {
+        fn get_name(&self) -> &'static str {
+            "failing"
+        }
+
+        fn get_config(&self) -> &SearcherConfig {
+            &self.config
+        }
+
+        async fn search(&self, _url: &str) -> ImageSearchResult {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(self.delay).await;
+            Err(anyhow!("service unavailable"))
+        }
+
+        async fn search_bytes(&self, _image: &[u8]) -> ImageSearchResult {
+            self.search("").await
+        }
+    }
+
+    fn searcher(timeout_secs: u64, delay: Duration) -> (GuardedSearcher, Arc<AtomicU32>) {
+        let calls = Arc::new(AtomicU32::new(0));
+        let inner = FailingSearcher {
+            config: SearcherConfig {
+                min_similarity: 0.0,
+                drop_low_confidence: false,
+                max_results: 1,
+                timeout_secs,
+                max_upload_bytes: 1024,
+            },
+            calls: calls.clone(),
+            delay,
+        };
+        (
+            GuardedSearcher::new(Box::new(inner), 2, Duration::from_secs(60)),
+            calls,
+        )
+    }
+
+    #[tokio::test]
+    async fn circuit_breaker_test() {
+        let (searcher, calls) = searcher(10, Duration::ZERO);
+        assert!(searcher.search("foo").await.is_err());
+        assert!(!searcher.is_open());
+        assert!(searcher.search("foo").await.is_err());
+        assert!(searcher.is_open());
+
+        let err = searcher.search("foo").await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<SearcherError>(),
+            Some(SearcherError::CircuitOpen(_))
+        ));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        let stats = searcher.get_stats();
+        assert_eq!((stats.failures, stats.skipped), (2, 1));
+    }
+
+    #[tokio::test]
+    async fn timeout_test() {
+        let (searcher, _) = searcher(0, Duration::from_secs(5));
+        let err = searcher.search("foo").await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<SearcherError>(),
+            Some(SearcherError::Timeout(_, _))
+        ));
+        assert_eq!(searcher.get_stats().timeouts, 1);
+    }
+}
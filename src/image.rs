@@ -1,7 +1,8 @@
 use crate::client::CLIENT;
 use crate::message::*;
-use crate::{cfg, utils};
-use anyhow::{anyhow, bail, Context, Result};
+use crate::naming::{self, NameFields};
+use crate::{cfg, local_search, utils};
+use anyhow::{anyhow, Context, Result};
 use lazy_static::lazy_static;
 use nanoid::nanoid;
 use regex::Regex;
@@ -62,7 +63,7 @@ async fn handle_request(url: &str, process: bool) -> Result<String> {
             .context("Failed to process the image")?;
         return Ok(path);
     }
-    return Ok(path);
+    Ok(path)
 }
 
 async fn get_twitter_image_url(url: &str) -> Result<Option<String>> {
@@ -104,25 +105,19 @@ async fn get_twitter_image_url(url: &str) -> Result<Option<String>> {
 }
 
 async fn download_image(url: &url::Url) -> Result<String> {
-    let response = CLIENT.get(url.as_str()).send().await?.error_for_status()?;
-    let content_type = response
-        .headers()
-        .get("Content-Type")
-        .ok_or_else(|| anyhow!("Cannot find Content-Type from response {}", url))?
-        .to_str()?;
-    let file_name = match utils::extract_filename_from_url(url) {
-        None => bail!("Failed to extract filename from url {}", url.as_str()),
-        Some((name, None)) => {
-            let extension = content_type
-                .strip_prefix("image/")
-                .ok_or_else(|| anyhow!("Unexpected Content-Type value {}", content_type))?;
-            format!("{}.{}", name, extension)
-        }
-        Some((name, Some(extension))) => format!("{}.{}", name, extension),
-    };
-    let path = format!("{}/{}", cfg::BOT_CONFIG.download_path, file_name);
-
-    let _ = utils::download_file_if_not_exists(response, &path).await?;
+    let request = CLIENT.get(url.as_str());
+    let response = utils::send_cloned(&request).await?;
+    let fields = NameFields::from_response(&response, "jpg");
+    let claim = naming::claim(
+        &naming::render(&cfg::BOT_CONFIG.naming.image, &fields),
+        url.as_str(),
+    )
+    .await?;
+
+    let _ = utils::download_file_if_not_exists(response, request, claim.path(), None, None).await?;
+    let path = naming::fix_extension(claim.path(), url.as_str()).await?;
+    claim.keep();
+    local_search::spawn_index_file(path.clone());
     Ok(path)
 }
 
@@ -1,22 +1,93 @@
 use anyhow::anyhow;
 use async_trait::async_trait;
+use lazy_static::lazy_static;
+use log::warn;
+use regex::Regex;
+use reqwest::multipart::Form;
 use std::collections::HashMap;
 use visdom::Vis;
 
+use crate::cfg;
 use crate::client::*;
 use crate::searcher::*;
 
-pub struct IqdbImageSearcher {}
+pub struct IqdbImageSearcher {
+    pub config: cfg::SearcherConfig,
+    /// Searches 3d.iqdb.org instead of iqdb.org.
+    pub is_3d: bool,
+    /// Names of the services to search, all services are searched when empty.
+    pub services: Vec<String>,
+}
+
+/// Names and ids of the services, and whether they are hosted on 3d.iqdb.org.
+static IQDB_SERVICES: [(&str, &str, bool); 10] = [
+    ("danbooru", "1", false),
+    ("konachan", "2", false),
+    ("yande.re", "3", false),
+    ("gelbooru", "4", false),
+    ("sankaku", "5", false),
+    ("e-shuushuu", "6", false),
+    ("zerochan", "11", false),
+    ("anime-pictures", "13", false),
+    ("3dbooru", "7", true),
+    ("idol", "9", true),
+];
+
+/// Warns about the configured services which iqdb does not know.
+pub fn check_services(services: &[String]) {
+    for service in services {
+        if !IQDB_SERVICES.iter().any(|(name, _, _)| name == service) {
+            warn!("unknown iqdb service {}, which is ignored", service);
+        }
+    }
+}
 
 #[async_trait]
 impl ImageSearcher for IqdbImageSearcher {
     fn get_name(&self) -> &'static str {
-        "iqdb"
+        if self.is_3d {
+            "3d.iqdb"
+        } else {
+            "iqdb"
+        }
+    }
+
+    fn get_config(&self) -> &cfg::SearcherConfig {
+        &self.config
     }
 
     async fn search(&self, url: &str) -> ImageSearchResult {
+        let services = match self.service_ids() {
+            Some(ids) if ids.is_empty() => return Ok(vec![]),
+            ids => ids
+                .unwrap_or_default()
+                .into_iter()
+                .map(|id| ("service[]", id))
+                .collect::<Vec<(&str, &str)>>(),
+        };
+        let response = CLIENT
+            .get(self.base_url())
+            .query(&[("url", url)])
+            .query(&services)
+            .send()
+            .await?
+            .error_for_status()?;
+        let html = response.text().await?;
+        self.parse_result(html.as_str())
+    }
+
+    async fn search_bytes(&self, image: &[u8]) -> ImageSearchResult {
+        let ids = match self.service_ids() {
+            Some(ids) if ids.is_empty() => return Ok(vec![]),
+            ids => ids.unwrap_or_default(),
+        };
+        let form = ids
+            .into_iter()
+            .fold(Form::new(), |form, id| form.text("service[]", id))
+            .part("file", image_part(image));
         let response = CLIENT
-            .get(format!("https://iqdb.org/?url={}", url))
+            .post(self.base_url())
+            .multipart(form)
             .send()
             .await?
             .error_for_status()?;
@@ -26,21 +97,177 @@ impl ImageSearcher for IqdbImageSearcher {
 }
 
 impl IqdbImageSearcher {
+    fn base_url(&self) -> &'static str {
+        if self.is_3d {
+            "https://3d.iqdb.org/"
+        } else {
+            "https://iqdb.org/"
+        }
+    }
+
+    /// Returns `None` when all services are searched. The ids are empty when none of the
+    /// configured services are hosted on this site, and nothing should be searched.
+    fn service_ids(&self) -> Option<Vec<&'static str>> {
+        if self.services.is_empty() {
+            return None;
+        }
+        Some(
+            IQDB_SERVICES
+                .iter()
+                .filter(|(name, _, is_3d)| {
+                    *is_3d == self.is_3d && self.services.iter().any(|service| service == name)
+                })
+                .map(|(_, id, _)| *id)
+                .collect(),
+        )
+    }
+
     fn parse_result(&self, html: &str) -> ImageSearchResult {
+        lazy_static! {
+            static ref SIMILARITY_REGEX: Regex = Regex::new(r"^(\d+)% similarity$").unwrap();
+            static ref RESOLUTION_REGEX: Regex =
+                Regex::new(r"^(\d+)×(\d+)(?: \[(\w+)\])?$").unwrap();
+            static ref TAGS_REGEX: Regex = Regex::new(r"Tags: (.+)$").unwrap();
+            static ref ICON_REGEX: Regex = Regex::new(r"/icon/([\w.-]+)\.ico").unwrap();
+        }
+
         let root = Vis::load(html).map_err(|e| anyhow!(e))?;
-        let target = root.find("#pages > div + div");
-        let mut source_url = target
-            .find("tr:nth-of-type(2) a")
-            .attr("href")
-            .ok_or_else(|| anyhow!("failed to find href of the result"))?
-            .to_string();
-        if source_url.starts_with("//") {
-            source_url = format!("https:{}", source_url);
+        // includes the possible matches hidden in #more1
+        let tables = root.find(".pages > div table");
+        let mut images = vec![];
+        for i in 0..tables.length() {
+            let table = tables.eq(i);
+            if table.find("th").text() == "Your image" {
+                continue;
+            }
+            let mut source_url = match table.find("td.image a").attr("href") {
+                Some(href) => href.to_string(),
+                None => continue,
+            };
+            if source_url.starts_with("//") {
+                source_url = format!("https:{}", source_url);
+            }
+            let cells = table.find("td").map(|_, td| td.text());
+            let similarity = match cells
+                .iter()
+                .find_map(|cell| SIMILARITY_REGEX.captures(cell))
+            {
+                Some(caps) => caps[1].parse::<f64>()? / 100.0,
+                None => continue,
+            };
+
+            let mut metadata: HashMap<String, String> = HashMap::new();
+            if let Some(caps) = cells
+                .iter()
+                .find_map(|cell| RESOLUTION_REGEX.captures(cell))
+            {
+                metadata.insert("分辨率".to_string(), format!("{}×{}", &caps[1], &caps[2]));
+                if let Some(rating) = caps.get(3) {
+                    metadata.insert("分级".to_string(), rating.as_str().to_string());
+                }
+            }
+            let services = table
+                .find("img.service-icon")
+                .map(|_, icon| {
+                    icon.get_attribute("src").and_then(|src| {
+                        ICON_REGEX
+                            .captures(&src.to_string())
+                            .map(|caps| caps[1].to_string())
+                    })
+                })
+                .into_iter()
+                .flatten()
+                .collect::<Vec<String>>();
+            if !services.is_empty() {
+                metadata.insert("站点".to_string(), services.join(", "));
+            }
+            if let Some(tags) = table.find("td.image img").attr("alt").and_then(|alt| {
+                TAGS_REGEX
+                    .captures(&alt.to_string())
+                    .map(|caps| caps[1].to_string())
+            }) {
+                metadata.insert(
+                    "标签".to_string(),
+                    tags.split_whitespace().collect::<Vec<&str>>().join(", "),
+                );
+            }
+
+            images.push(SourceImage {
+                url: source_url,
+                searcher: self.get_name(),
+                similarity,
+                low_confidence: false,
+                metadata,
+                preview: None,
+            });
         }
-        Ok(Some(SourceImage {
-            url: source_url,
-            searcher: self.get_name(),
-            metadata: HashMap::default(),
-        }))
+        Ok(images)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cfg::SearcherConfig;
+    use crate::iqdb::IqdbImageSearcher;
+
+    fn searcher(is_3d: bool, services: &[&str]) -> IqdbImageSearcher {
+        IqdbImageSearcher {
+            config: SearcherConfig::iqdb(),
+            is_3d,
+            services: services.iter().map(|service| service.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn service_ids_test() {
+        assert_eq!(
+            searcher(false, &["danbooru", "yande.re", "idol", "foo"]).service_ids(),
+            Some(vec!["1", "3"])
+        );
+        assert_eq!(
+            searcher(true, &["danbooru", "idol"]).service_ids(),
+            Some(vec!["9"])
+        );
+        assert_eq!(searcher(true, &["danbooru"]).service_ids(), Some(vec![]));
+        assert_eq!(searcher(false, &[]).service_ids(), None);
+    }
+
+    #[test]
+    fn parse_iqdb_result() {
+        let images = searcher(false, &[])
+            .parse_result(
+                r###"<!DOCTYPE html>
+<html>
+<head><title>Multi-service image search - Search results</title></head>
+<body>
+<div id='pages' class='pages'>
+<div><table><tr><th>Your image</th></tr><tr><td class='image'><img src='/thu/thu_0123abcd.jpg' alt=""></td></tr><tr><td>500×700 JPEG</td></tr></table></div>
+<div><table><tr><th>Best match</th></tr><tr><td class='image'><a href="//danbooru.donmai.us/posts/5678901"><img src='/danbooru/0/1/2/0123456789abcdef.jpg' alt="Rating: s Score: 12 Tags: 1girl hatsune_miku vocaloid" title="Rating: s Score: 12 Tags: 1girl hatsune_miku vocaloid" width='150' height='113'></a></td></tr><tr><td><img alt="icon" src="/icon/danbooru.ico" class="service-icon">Danbooru <img alt="icon" src="/icon/gelbooru.ico" class="service-icon"><a href="https://gelbooru.com/index.php?page=post&amp;s=view&amp;id=7654321">Gelbooru</a></td></tr><tr><td>1200×1600 [Safe]</td></tr><tr><td>95% similarity</td></tr></table></div>
+<div><table><tr><th>Additional match</th></tr><tr><td class='image'><a href="https://yande.re/post/show/1002003"><img src='/moe.imouto/a/b/abcdef.jpg' alt="Rating: q Score: 30 Tags: dress seifuku" width='150' height='200'></a></td></tr><tr><td><img alt="icon" src="/icon/yande.re.ico" class="service-icon">yande.re</td></tr><tr><td>2400×3200 [Ero]</td></tr><tr><td>91% similarity</td></tr></table></div>
+<div><table><tr><th>No relevant matches</th></tr></table></div>
+</div>
+<div id="more1"><div class="pages">
+<div><table><tr><th>Possible match</th></tr><tr><td class='image'><a href="https://e-shuushuu.net/image/1234/"><img src='/e-shuushuu/x.jpg' alt=""></a></td></tr><tr><td><img alt="icon" src="/icon/e-shuushuu.ico" class="service-icon">e-shuushuu</td></tr><tr><td>800×600</td></tr><tr><td>62% similarity</td></tr></table></div>
+</div></div>
+</body>
+</html>"###,
+            )
+            .unwrap();
+        assert_eq!(images.len(), 3);
+
+        assert_eq!(images[0].url, "https://danbooru.donmai.us/posts/5678901");
+        assert_eq!(images[0].similarity, 0.95);
+        assert_eq!(images[0].metadata["分辨率"], "1200×1600");
+        assert_eq!(images[0].metadata["分级"], "Safe");
+        assert_eq!(images[0].metadata["站点"], "danbooru, gelbooru");
+        assert_eq!(images[0].metadata["标签"], "1girl, hatsune_miku, vocaloid");
+
+        assert_eq!(images[1].url, "https://yande.re/post/show/1002003");
+        assert_eq!(images[1].metadata["分级"], "Ero");
+        assert_eq!(images[1].metadata["站点"], "yande.re");
+
+        assert_eq!(images[2].similarity, 0.62);
+        assert!(!images[2].metadata.contains_key("分级"));
+        assert!(!images[2].metadata.contains_key("标签"));
     }
 }
@@ -0,0 +1,595 @@
+use crate::archive::{ArchiveRecord, ARCHIVE};
+use crate::database::*;
+use crate::message::*;
+use crate::messages::{self, MessageKey};
+use crate::phash::{hamming_distance, ImageHash};
+use crate::repost::{self, RepostIndex, RepostRecord};
+use crate::{cfg, phash, searcher, utils};
+use anyhow::Result;
+use lazy_static::lazy_static;
+use log::{error, info, warn};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+lazy_static! {
+    static ref REPLY_ID_REGEX: Regex = Regex::new(r"id=([^]]+)]").unwrap();
+    /// Hashes of the files in `download_path`, keyed by their paths.
+    static ref LOCAL_HASHES: sled::Tree = DATABASE
+        .open_tree("local_hashes")
+        .expect("failed to open local hashes");
+    /// Built on the first search, and updated as images are downloaded, archived or indexed.
+    static ref INDEX: Mutex<IndexState> = Mutex::new(IndexState::Unbuilt);
+    /// Held by the search building the index, so that concurrent searches wait for it.
+    static ref BUILDING: Mutex<()> = Mutex::new(());
+}
+
+static IMAGE_EXTENSIONS: [&str; 6] = ["jpg", "jpeg", "png", "gif", "webp", "bmp"];
+static RESULT_HEADER: &str = "🔍 本地相似图片 👇👇👇";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LocalSource {
+    File {
+        path: String,
+    },
+    GroupMessage {
+        group_id: i64,
+        user_id: i64,
+        message_id: i32,
+        time: u64,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct LocalImage {
+    pub hash: ImageHash,
+    pub source: LocalSource,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedHash {
+    modified: u64,
+    dhash: u64,
+    phash: u64,
+}
+
+/// A metric tree over the hamming distance between dHashes.
+pub struct BkTree<T> {
+    nodes: Vec<BkNode<T>>,
+}
+
+struct BkNode<T> {
+    hash: u64,
+    item: T,
+    children: HashMap<u32, usize>,
+}
+
+impl<T> Default for BkTree<T> {
+    fn default() -> Self {
+        BkTree { nodes: vec![] }
+    }
+}
+
+impl<T> BkTree<T> {
+    pub fn insert(&mut self, hash: u64, item: T) {
+        let index = self.nodes.len();
+        let mut i = 0;
+        while i < index {
+            let distance = hamming_distance(self.nodes[i].hash, hash);
+            match self.nodes[i].children.get(&distance) {
+                Some(child) => i = *child,
+                None => {
+                    self.nodes[i].children.insert(distance, index);
+                    break;
+                }
+            }
+        }
+        self.nodes.push(BkNode {
+            hash,
+            item,
+            children: HashMap::new(),
+        });
+    }
+
+    /// Returns the items within `max_distance` of the hash along with their distances.
+    pub fn find(&self, hash: u64, max_distance: u32) -> Vec<(u32, &T)> {
+        let mut result = vec![];
+        let mut stack = if self.nodes.is_empty() {
+            vec![]
+        } else {
+            vec![0]
+        };
+        while let Some(i) = stack.pop() {
+            let node = &self.nodes[i];
+            let distance = hamming_distance(node.hash, hash);
+            if distance <= max_distance {
+                result.push((distance, &node.item));
+            }
+            // by the triangle inequality, only these subtrees may contain matches
+            stack.extend(
+                node.children
+                    .iter()
+                    .filter(|(d, _)| d.abs_diff(distance) <= max_distance)
+                    .map(|(_, child)| *child),
+            );
+        }
+        result
+    }
+}
+
+/// The local images keyed by their paths or messages, along with a BK-tree of their keys.
+#[derive(Default)]
+pub struct LocalIndex {
+    images: HashMap<String, LocalImage>,
+    tree: BkTree<String>,
+    /// Whether images are removed or replaced since the tree is built.
+    stale: bool,
+}
+
+impl LocalIndex {
+    pub fn insert(&mut self, image: LocalImage) {
+        let key = match &image.source {
+            LocalSource::File { path } => path.clone(),
+            LocalSource::GroupMessage {
+                group_id,
+                message_id,
+                ..
+            } => format!("{}:{}", group_id, message_id),
+        };
+        let dhash = image.hash.dhash;
+        match self.images.insert(key.clone(), image) {
+            Some(previous) if previous.hash.dhash == dhash => {}
+            Some(_) => self.stale = true,
+            None => self.tree.insert(dhash, key),
+        }
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        if self.images.remove(key).is_some() {
+            self.stale = true;
+        }
+    }
+
+    /// Returns the images within `max_distance`, sorted by their similarity.
+    pub fn rank(
+        &mut self,
+        hash: &ImageHash,
+        max_distance: u32,
+        max_results: usize,
+    ) -> Vec<(f64, LocalImage)> {
+        if self.stale {
+            self.tree = BkTree::default();
+            for (key, image) in &self.images {
+                self.tree.insert(image.hash.dhash, key.clone());
+            }
+            self.stale = false;
+        }
+        let mut result = self
+            .tree
+            .find(hash.dhash, max_distance)
+            .into_iter()
+            .filter_map(|(distance, key)| {
+                let image = self.images.get(key)?;
+                let distance = distance + hamming_distance(image.hash.phash, hash.phash);
+                Some((1.0 - distance as f64 / 128.0, image.clone()))
+            })
+            .collect::<Vec<(f64, LocalImage)>>();
+        result.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+        result.truncate(max_results);
+        result
+    }
+}
+
+/// The index is built without holding the lock, images indexed meanwhile are kept aside and
+/// added once it is swapped in.
+enum IndexState {
+    Unbuilt,
+    Building(Vec<LocalImage>),
+    Built(LocalIndex),
+}
+
+impl IndexState {
+    fn is_unbuilt(&self) -> bool {
+        matches!(self, IndexState::Unbuilt)
+    }
+
+    fn insert(&mut self, image: LocalImage) {
+        match self {
+            IndexState::Unbuilt => {}
+            IndexState::Building(pending) => pending.push(image),
+            IndexState::Built(index) => index.insert(image),
+        }
+    }
+}
+
+/// Adds a downloaded or archived file to the index once it is built, which hashes the files
+/// present by then.
+pub fn index_file(path: &Path) {
+    if INDEX.lock().unwrap().is_unbuilt() || !is_image(path) {
+        return;
+    }
+    match hash_file(path, &LOCAL_HASHES) {
+        Ok(hash) => INDEX.lock().unwrap().insert(LocalImage {
+            hash,
+            source: LocalSource::File {
+                path: path.to_string_lossy().to_string(),
+            },
+        }),
+        Err(err) => warn!("failed to hash {}: {}", path.display(), err),
+    }
+}
+
+/// Hashes the file in the background, see `index_file`.
+pub fn spawn_index_file(path: String) {
+    tokio::task::spawn_blocking(move || index_file(Path::new(&path)));
+}
+
+/// Adds an image indexed for repost detection to the index once it is built.
+pub fn index_group_image(group_id: i64, record: &RepostRecord) {
+    INDEX.lock().unwrap().insert(group_image(group_id, record));
+}
+
+pub async fn on_group_message(message: OneBotGroupMessage) -> Option<BotResponseAction> {
+    let OneBotGroupMessage {
+        message,
+        message_id,
+        group_id,
+        self_id,
+        ..
+    } = message;
+    if !message.contains("[CQ:reply") || !message.contains("search-local") {
+        return None;
+    }
+    let reply_id = REPLY_ID_REGEX.captures(&message)?[1].parse::<i32>().ok()?;
+
+    let reply = |message: String| BotResponseAction::GroupMessage {
+        group_id,
+        message: format!("[CQ:reply,id={}]{}", message_id, message),
+    };
+    let key = MessageKey {
+        self_id,
+        group_id,
+        message_id: reply_id,
+    };
+    let image_url = match messages::get(key) {
+        Ok(Some(image)) => image.url,
+        Ok(None) => return Some(reply("找不到回复的图片".to_string())),
+        Err(err) => {
+            error!("failed to get record from database: {}", err);
+            return None;
+        }
+    };
+    match search(&image_url).await {
+        Ok(images) if images.is_empty() => Some(reply("本地没有找到相似的图片".to_string())),
+        Ok(images) => Some(reply(parse_result(&images))),
+        Err(err) => Some(reply(format!("本地搜索时出错: {:#?}", err))),
+    }
+}
+
+async fn search(url: &str) -> Result<Vec<(f64, LocalImage)>> {
+    let image = searcher::fetch_image(url).await?;
+    tokio::task::spawn_blocking(move || {
+        let hash = phash::hash_image(&image)?;
+        let config = &cfg::BOT_CONFIG.local_search;
+        ensure_index();
+        let mut index = INDEX.lock().unwrap();
+        let IndexState::Built(index) = &mut *index else {
+            unreachable!("the index is built by ensure_index");
+        };
+        loop {
+            let images = index.rank(&hash, config.max_distance, config.max_results);
+            // files may be removed by the user or the archive sweeper since they are indexed
+            let missing = images
+                .iter()
+                .filter_map(|(_, image)| match &image.source {
+                    LocalSource::File { path } if !Path::new(path).exists() => Some(path.clone()),
+                    _ => None,
+                })
+                .collect::<Vec<String>>();
+            if missing.is_empty() {
+                return Ok(images);
+            }
+            for path in missing {
+                index.remove(&path);
+                LOCAL_HASHES.remove(path.as_bytes())?;
+            }
+        }
+    })
+    .await?
+}
+
+/// Builds the index on the first search, hashing the files without blocking `index_file` and
+/// `index_group_image`.
+fn ensure_index() {
+    let _building = BUILDING.lock().unwrap();
+    {
+        let mut state = INDEX.lock().unwrap();
+        if !state.is_unbuilt() {
+            return;
+        }
+        *state = IndexState::Building(vec![]);
+    }
+    let mut index = build_index();
+    let mut state = INDEX.lock().unwrap();
+    if let IndexState::Building(pending) = std::mem::replace(&mut *state, IndexState::Unbuilt) {
+        for image in pending {
+            index.insert(image);
+        }
+    }
+    *state = IndexState::Built(index);
+}
+
+fn build_index() -> LocalIndex {
+    match prune_hashes(&LOCAL_HASHES) {
+        Ok(0) => {}
+        Ok(removed) => info!("removed {} hashes of missing files", removed),
+        Err(err) => error!("failed to prune local hashes: {:#?}", err),
+    }
+    let mut index = LocalIndex::default();
+    let images = hash_files(Path::new(&cfg::BOT_CONFIG.download_path), &LOCAL_HASHES);
+    for image in images.into_iter().chain(group_images(&DATABASE)) {
+        index.insert(image);
+    }
+    index
+}
+
+/// Removes the cached hashes of the files which no longer exist.
+pub fn prune_hashes(cache: &sled::Tree) -> Result<usize> {
+    let mut removed = 0;
+    for key in cache.iter().keys() {
+        let key = key?;
+        if !Path::new(&*String::from_utf8_lossy(&key)).exists() {
+            cache.remove(key)?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+fn is_image(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| IMAGE_EXTENSIONS.contains(&extension.to_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+/// Hashes the images under the directory recursively, reusing the cached hashes of
+/// unmodified files.
+pub fn hash_files(dir: &Path, cache: &sled::Tree) -> Vec<LocalImage> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            warn!("failed to read directory {}: {}", dir.display(), err);
+            return vec![];
+        }
+    };
+    let mut images = vec![];
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            images.extend(hash_files(&path, cache));
+            continue;
+        }
+        if !is_image(&path) {
+            continue;
+        }
+        match hash_file(&path, cache) {
+            Ok(hash) => images.push(LocalImage {
+                hash,
+                source: LocalSource::File {
+                    path: path.to_string_lossy().to_string(),
+                },
+            }),
+            Err(err) => warn!("failed to hash {}: {}", path.display(), err),
+        }
+    }
+    images
+}
+
+fn hash_file(path: &Path, cache: &sled::Tree) -> Result<ImageHash> {
+    let modified = std::fs::metadata(path)?
+        .modified()?
+        .duration_since(UNIX_EPOCH)?
+        .as_secs();
+    let key = path.to_string_lossy().to_string();
+    if let Some(value) = cache.get(&key)? {
+        let cached: CachedHash = serde_json::from_slice(&value)?;
+        if cached.modified == modified {
+            return Ok(ImageHash {
+                dhash: cached.dhash,
+                phash: cached.phash,
+            });
+        }
+    }
+
+    let hash = phash::hash_image(&std::fs::read(path)?)?;
+    let cached = CachedHash {
+        modified,
+        dhash: hash.dhash,
+        phash: hash.phash,
+    };
+    cache.insert(key, serde_json::to_vec(&cached)?)?;
+    Ok(hash)
+}
+
+/// Returns the images indexed for repost detection.
+fn group_images(db: &sled::Db) -> Vec<LocalImage> {
+    let mut images = vec![];
+    for group_id in repost::indexed_groups(db) {
+        let records = RepostIndex::open(db, group_id).and_then(|index| index.records());
+        match records {
+            Ok(records) => {
+                images.extend(records.iter().map(|record| group_image(group_id, record)))
+            }
+            Err(err) => error!("failed to read images of group {}: {:#?}", group_id, err),
+        }
+    }
+    images
+}
+
+fn group_image(group_id: i64, record: &RepostRecord) -> LocalImage {
+    LocalImage {
+        hash: ImageHash {
+            dhash: record.dhash,
+            phash: record.phash,
+        },
+        source: LocalSource::GroupMessage {
+            group_id,
+            user_id: record.user_id,
+            message_id: record.message_id,
+            time: record.time,
+        },
+    }
+}
+
+/// Returns the first post of a file in the group image archive, which is named by its md5.
+fn archived_record(path: &str) -> Option<ArchiveRecord> {
+    let md5 = Path::new(path).file_stem()?.to_str()?;
+    match ARCHIVE.first_record(md5) {
+        Ok(record) => record,
+        Err(err) => {
+            warn!("failed to get archived image {}: {:#?}", md5, err);
+            None
+        }
+    }
+}
+
+fn parse_result(images: &[(f64, LocalImage)]) -> String {
+    images
+        .iter()
+        .fold(
+            format!("{}\n\n", RESULT_HEADER),
+            |mut result, (similarity, image)| {
+                let source = match &image.source {
+                    LocalSource::File { path } => match archived_record(path) {
+                        Some(record) => format!(
+                            "存档 {}：群 {} 中 {} 于 {} 发送（消息 {}）\n[CQ:image,file=file://{}]",
+                            record.id,
+                            record.group_id,
+                            record.user_id,
+                            utils::format_timestamp(record.time),
+                            record.message_id,
+                            path
+                        ),
+                        None => format!("文件：{}\n[CQ:image,file=file://{}]", path, path),
+                    },
+                    LocalSource::GroupMessage {
+                        group_id,
+                        user_id,
+                        message_id,
+                        time,
+                    } => format!(
+                        "群 {} 中 {} 于 {} 发送（消息 {}）",
+                        group_id,
+                        user_id,
+                        utils::format_timestamp(*time),
+                        message_id
+                    ),
+                };
+                result.push_str(
+                    format!("⚠️ 相似度 {:.1}%\n{}\n\n", similarity * 100.0, source).as_str(),
+                );
+                result
+            },
+        )
+        .trim_end()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::local_search::{
+        hash_files, prune_hashes, BkTree, LocalImage, LocalIndex, LocalSource,
+    };
+    use crate::phash::ImageHash;
+
+    #[test]
+    fn bk_tree_test() {
+        let mut tree = BkTree::default();
+        for (i, hash) in [0u64, 0b1, 0b11, 0b1111, !0, 0b1 << 63]
+            .into_iter()
+            .enumerate()
+        {
+            tree.insert(hash, i);
+        }
+        let mut found = tree.find(0, 2);
+        found.sort();
+        assert_eq!(found, vec![(0, &0), (1, &1), (1, &5), (2, &2)]);
+        assert!(BkTree::<usize>::default().find(0, 64).is_empty());
+    }
+
+    #[test]
+    fn local_index_test() {
+        let image = |dhash: u64, phash: u64, message_id: i32| LocalImage {
+            hash: ImageHash { dhash, phash },
+            source: LocalSource::GroupMessage {
+                group_id: 1,
+                user_id: 2,
+                message_id,
+                time: 0,
+            },
+        };
+        let mut index = LocalIndex::default();
+        for image in [
+            image(0b1, 0b1111, 1),
+            image(0b11, 0, 2),
+            image(0, 0, 3),
+            image(0xffff, 0, 4),
+        ] {
+            index.insert(image);
+        }
+        let hash = ImageHash { dhash: 0, phash: 0 };
+        let ranked = index.rank(&hash, 4, 2);
+        assert_eq!(ranked.len(), 2);
+        assert_eq!(ranked[0].0, 1.0);
+        assert!(matches!(
+            ranked[0].1.source,
+            LocalSource::GroupMessage { message_id: 3, .. }
+        ));
+        assert!(matches!(
+            ranked[1].1.source,
+            LocalSource::GroupMessage { message_id: 2, .. }
+        ));
+
+        // removed and replaced images are left out once the tree is rebuilt
+        index.remove("1:3");
+        index.insert(image(0xff00, 0, 2));
+        let ranked = index.rank(&hash, 4, 2);
+        assert_eq!(ranked.len(), 1);
+        assert!(matches!(
+            ranked[0].1.source,
+            LocalSource::GroupMessage { message_id: 1, .. }
+        ));
+    }
+
+    #[test]
+    fn hash_files_test() {
+        let dir = std::env::temp_dir().join(format!("local_search_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("nested")).unwrap();
+        let image =
+            image::RgbImage::from_fn(64, 64, |x, y| image::Rgb([(x * 4) as u8, (y * 4) as u8, 0]));
+        image.save(dir.join("a.png")).unwrap();
+        std::fs::copy(dir.join("a.png"), dir.join("nested/b.JPG")).unwrap();
+        std::fs::write(dir.join("c.mp4"), b"not an image").unwrap();
+        std::fs::write(dir.join("d.png"), b"not an image").unwrap();
+
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let cache = db.open_tree("local_hashes").unwrap();
+        let images = hash_files(&dir, &cache);
+        assert_eq!(images.len(), 2);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(images[0].hash, images[1].hash);
+        // the cached hashes are returned for unmodified files
+        let cached = hash_files(&dir, &cache);
+        assert_eq!(cached[0].hash, images[0].hash);
+
+        std::fs::remove_file(dir.join("a.png")).unwrap();
+        assert_eq!(prune_hashes(&cache).unwrap(), 1);
+        assert_eq!(cache.len(), 1);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
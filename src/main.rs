@@ -1,13 +1,32 @@
+mod aggregator;
+mod archive;
 mod ascii2d;
+mod backup;
+mod booru;
+mod cache;
 mod cfg;
 mod client;
 mod database;
 mod download;
+mod download_guard;
+mod download_queue;
+mod ehentai;
+mod health;
 mod image;
 mod iqdb;
+mod local_search;
 mod message;
+mod messages;
+mod naming;
+mod phash;
+mod pixiv;
+mod repost;
+mod retention;
 mod saucenao;
 mod searcher;
+mod sender;
+mod storage;
+mod tracemoe;
 mod utils;
 
 use crate::cfg::*;
@@ -24,6 +43,15 @@ use tokio::sync::Mutex;
 async fn main() {
     pretty_env_logger::init();
 
+    let args = std::env::args().skip(1).collect::<Vec<String>>();
+    if let Some(result) = backup::run_command(&args) {
+        match result {
+            Ok(message) => info!("{}", message),
+            Err(err) => error!("{:#?}", err),
+        }
+        return;
+    }
+
     let (streams, _) = tokio_tungstenite::connect_async(&BOT_CONFIG.ws_url)
         .await
         .expect("failed to connect to websocket server");
@@ -31,6 +59,12 @@ async fn main() {
 
     let (mut write, read) = streams.split();
     let (tx, mut rx) = mpsc::channel::<BotResponseAction>(128);
+    sender::init(tx.clone());
+    storage::migrate();
+    archive::spawn_sweeper();
+    retention::spawn_sweeper();
+    backup::spawn_scheduler();
+    download_queue::resume();
 
     tokio::spawn(async move {
         while let Some(ref message) = rx.recv().await {
@@ -62,20 +96,39 @@ async fn main() {
 
             let messages_to_send: Vec<BotResponseAction> = match message {
                 OneBotMessageWrapper::Message(OneBotMessage::Message(message)) => match message {
-                    OneBotUserMessage::Group(message) => [
-                        searcher::on_group_message(message.clone()).await,
-                        download::on_group_message(message.clone()).await,
-                        image::on_group_message(message.clone()).await,
+                    OneBotUserMessage::Group(message) => {
+                        searcher::on_group_message(message.clone())
+                            .await
+                            .into_iter()
+                            .chain(pixiv::on_group_message(message.clone()).await)
+                            .chain(
+                                [
+                                    download::on_group_message(message.clone()).await,
+                                    image::on_group_message(message.clone()).await,
+                                    cache::on_group_message(message.clone()).await,
+                                    health::on_group_message(message.clone()).await,
+                                    repost::on_group_message(message.clone()).await,
+                                    local_search::on_group_message(message.clone()).await,
+                                    archive::on_group_message(message.clone()).await,
+                                    retention::on_group_message(message.clone()).await,
+                                    backup::on_group_message(message.clone()).await,
+                                ]
+                                .into_iter()
+                                .flatten(),
+                            )
+                            .collect()
+                    }
+                    OneBotUserMessage::Private(message) => [
+                        download::on_private_message(message.clone()).await,
+                        cache::on_private_message(message.clone()).await,
+                        health::on_private_message(message.clone()).await,
+                        archive::on_private_message(message.clone()).await,
+                        retention::on_private_message(message.clone()).await,
+                        backup::on_private_message(message).await,
                     ]
                     .into_iter()
                     .flatten()
                     .collect(),
-                    OneBotUserMessage::Private(message) => {
-                        [download::on_private_message(message).await]
-                            .into_iter()
-                            .flatten()
-                            .collect()
-                    }
                 },
                 _ => vec![],
             };
@@ -7,6 +7,7 @@ pub enum OneBotMessage {
     Message(OneBotUserMessage),
 
     #[serde(rename = "meta_event")]
+    #[allow(dead_code)]
     Meta { meta_event_type: String },
 }
 
@@ -22,6 +23,7 @@ pub enum OneBotUserMessage {
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct OneBotPrivateMessage {
+    #[allow(dead_code)]
     pub message_id: i32,
     pub user_id: i64,
     pub message: String,
@@ -29,6 +31,7 @@ pub struct OneBotPrivateMessage {
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct OneBotGroupMessage {
+    pub self_id: i64,
     pub message_id: i32,
     pub group_id: i64,
     pub user_id: i64,
@@ -39,6 +42,7 @@ pub struct OneBotGroupMessage {
 #[serde(untagged)]
 pub enum OneBotMessageWrapper {
     Message(OneBotMessage),
+    #[allow(dead_code)]
     Other(serde_json::Value),
 }
 
@@ -53,6 +57,28 @@ pub enum BotResponseAction {
         file: String,
         name: String,
     },
+    #[serde(rename = "upload_private_file")]
+    PrivateFile {
+        user_id: i64,
+        file: String,
+        name: String,
+    },
     #[serde(rename = "send_private_msg")]
     PrivateMessage { user_id: i64, message: String },
+    #[serde(rename = "send_group_forward_msg")]
+    GroupForwardMessage {
+        group_id: i64,
+        messages: Vec<ForwardMessageNode>,
+    },
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", content = "data")]
+pub enum ForwardMessageNode {
+    #[serde(rename = "node")]
+    Node {
+        name: String,
+        uin: i64,
+        content: String,
+    },
 }
@@ -0,0 +1,223 @@
+use crate::storage::{Repository, STORAGE};
+use crate::{cfg, retention, utils};
+use anyhow::Result;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+/// Message ids are only unique within a group of a bot account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageKey {
+    pub self_id: i64,
+    pub group_id: i64,
+    pub message_id: i32,
+}
+
+impl MessageKey {
+    /// Scope of the messages migrated from the flat `image_url:{message_id}` keys. Their groups
+    /// are unknown, so replies never resolve to them, and they are kept until they expire.
+    pub fn legacy(message_id: i32) -> Self {
+        MessageKey {
+            self_id: 0,
+            group_id: 0,
+            message_id,
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; 20] {
+        let mut bytes = [0; 20];
+        bytes[..8].copy_from_slice(&self.self_id.to_be_bytes());
+        bytes[8..16].copy_from_slice(&self.group_id.to_be_bytes());
+        bytes[16..].copy_from_slice(&self.message_id.to_be_bytes());
+        bytes
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct StoredImage {
+    pub url: String,
+    pub md5: Option<String>,
+    pub time: u64,
+}
+
+/// Returns the image of the message, or `None` once it is older than the TTL of the `messages`
+/// tree, even if the sweeper has not removed it yet.
+pub fn get(key: MessageKey) -> Result<Option<StoredImage>> {
+    let ttl = cfg::BOT_CONFIG.retention.tree_ttls.get("messages").copied();
+    get_in(&STORAGE.messages, key, ttl, utils::unix_timestamp())
+}
+
+fn get_in(
+    repository: &Repository<MessageKey, StoredImage>,
+    key: MessageKey,
+    ttl: Option<u64>,
+    now: u64,
+) -> Result<Option<StoredImage>> {
+    let image = match repository.get(&key)? {
+        Some(image) => image,
+        None => return Ok(None),
+    };
+    if ttl.is_some_and(|ttl| image.time.saturating_add(ttl) < now) {
+        repository.remove(&key)?;
+        return Ok(None);
+    }
+    Ok(Some(image))
+}
+
+/// Moves the flat `image_url:{message_id}` and `image_md5:{message_id}` keys of the default
+/// tree into the legacy scope of the repository. Values stored without timestamps are treated
+/// as stored at `now`, so that they live for a full TTL after the migration.
+pub fn migrate_legacy_keys(
+    db: &sled::Db,
+    repository: &Repository<MessageKey, StoredImage>,
+    now: u64,
+) -> Result<usize> {
+    let mut migrated = 0;
+    for item in db.scan_prefix("image_url:") {
+        let (key, value) = item?;
+        let message_id = match std::str::from_utf8(&key)?
+            .strip_prefix("image_url:")
+            .and_then(|id| id.parse::<i32>().ok())
+        {
+            Some(message_id) => message_id,
+            None => {
+                warn!("malformed legacy key {}", String::from_utf8_lossy(&key));
+                continue;
+            }
+        };
+        let md5_key = format!("image_md5:{}", message_id);
+        let (time, url) = retention::decode(&value, now);
+        let md5 = db.get(&md5_key)?.map(|md5| retention::decode(&md5, now).1);
+        repository.insert(
+            &MessageKey::legacy(message_id),
+            &StoredImage { url, md5, time },
+        )?;
+        db.remove(&key)?;
+        db.remove(&md5_key)?;
+        migrated += 1;
+    }
+    // md5s without urls are useless
+    for item in db.scan_prefix("image_md5:") {
+        db.remove(item?.0)?;
+    }
+    Ok(migrated)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::messages::{get_in, migrate_legacy_keys, MessageKey, StoredImage};
+    use crate::storage::Repository;
+
+    #[test]
+    fn scoped_keys_test() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let repository = Repository::open(&db, "messages").unwrap();
+        let key = |group_id: i64| MessageKey {
+            self_id: 10000,
+            group_id,
+            message_id: -42,
+        };
+        repository
+            .insert(
+                &key(1),
+                &StoredImage {
+                    url: "a".to_string(),
+                    md5: None,
+                    time: 1,
+                },
+            )
+            .unwrap();
+        repository
+            .insert(
+                &key(2),
+                &StoredImage {
+                    url: "b".to_string(),
+                    md5: None,
+                    time: 1,
+                },
+            )
+            .unwrap();
+
+        assert_eq!(repository.get(&key(1)).unwrap().unwrap().url, "a");
+        assert_eq!(repository.get(&key(2)).unwrap().unwrap().url, "b");
+        assert_eq!(repository.get(&key(3)).unwrap(), None);
+        // the legacy scope is not a fallback for other groups
+        repository
+            .insert(
+                &MessageKey::legacy(-42),
+                &StoredImage {
+                    url: "c".to_string(),
+                    md5: None,
+                    time: 1,
+                },
+            )
+            .unwrap();
+        assert_eq!(get_in(&repository, key(3), None, 1).unwrap(), None);
+    }
+
+    #[test]
+    fn get_expired_test() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let repository = Repository::open(&db, "messages").unwrap();
+        let key = MessageKey::legacy(1);
+        repository
+            .insert(
+                &key,
+                &StoredImage {
+                    url: "a".to_string(),
+                    md5: None,
+                    time: 1000,
+                },
+            )
+            .unwrap();
+
+        assert_eq!(
+            get_in(&repository, key, Some(100), 1100).unwrap(),
+            Some(StoredImage {
+                url: "a".to_string(),
+                md5: None,
+                time: 1000
+            })
+        );
+        assert_eq!(
+            get_in(&repository, key, None, 5000).unwrap().unwrap().url,
+            "a"
+        );
+        assert_eq!(get_in(&repository, key, Some(100), 1101).unwrap(), None);
+        assert_eq!(repository.count(), 0);
+    }
+
+    #[test]
+    fn migrate_legacy_keys_test() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let repository = Repository::open(&db, "messages").unwrap();
+        db.insert("image_url:1", "https://example.com/1").unwrap();
+        db.insert("image_md5:1", "0123").unwrap();
+        db.insert(
+            "image_url:2",
+            r#"{"time":100,"value":"https://example.com/2"}"#,
+        )
+        .unwrap();
+        db.insert("image_md5:3", "4567").unwrap();
+        db.insert("other", "value").unwrap();
+
+        assert_eq!(migrate_legacy_keys(&db, &repository, 1000).unwrap(), 2);
+        assert_eq!(
+            repository.get(&MessageKey::legacy(1)).unwrap(),
+            Some(StoredImage {
+                url: "https://example.com/1".to_string(),
+                md5: Some("0123".to_string()),
+                time: 1000
+            })
+        );
+        assert_eq!(
+            repository.get(&MessageKey::legacy(2)).unwrap(),
+            Some(StoredImage {
+                url: "https://example.com/2".to_string(),
+                md5: None,
+                time: 100
+            })
+        );
+        assert_eq!(db.len(), 1);
+        assert_eq!(migrate_legacy_keys(&db, &repository, 1000).unwrap(), 0);
+    }
+}
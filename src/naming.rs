@@ -0,0 +1,435 @@
+use crate::storage::{Repository, STORAGE};
+use crate::{cfg, utils};
+use anyhow::Result;
+use lazy_static::lazy_static;
+use log::{error, info, warn};
+use nanoid::nanoid;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::Response;
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tokio::io::AsyncReadExt;
+use tokio::sync::OwnedMutexGuard;
+
+/// Maximum length in characters of a sanitized path segment.
+const MAX_SEGMENT_LENGTH: usize = 100;
+
+/// Values of the placeholders in a naming template, e.g. `{site}/{author}/{id}_{index}.{ext}`.
+/// Absent values are rendered as `unknown`.
+#[derive(Debug, Default)]
+pub struct NameFields {
+    pub site: Option<String>,
+    pub author: Option<String>,
+    pub id: Option<String>,
+    pub index: Option<usize>,
+    /// The name of the file in the url, without its extension.
+    pub name: Option<String>,
+    pub ext: String,
+}
+
+impl NameFields {
+    /// Fills the site and the name from the final url of the response, and the extension from
+    /// its Content-Type, the url or `default_ext` in order.
+    pub fn from_response(response: &Response, default_ext: &str) -> Self {
+        let url = response.url();
+        let (name, url_ext) = match utils::extract_filename_from_url(url) {
+            Some((name, ext)) => (Some(name.to_string()), ext),
+            None => (None, None),
+        };
+        let ext = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(extension_of_content_type)
+            .or_else(|| url_ext.filter(|ext| is_extension(ext)))
+            .unwrap_or(default_ext);
+        NameFields {
+            site: url
+                .host_str()
+                .map(|host| host.trim_start_matches("www.").to_string()),
+            name,
+            ext: ext.to_lowercase(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Renders a template into a relative path, whose segments are sanitized.
+pub fn render(template: &str, fields: &NameFields) -> String {
+    let field = |value: Option<&str>| sanitize(value.unwrap_or("unknown"));
+    let index = fields.index.map(|index| index.to_string());
+    template
+        .replace("{site}", &field(fields.site.as_deref()))
+        .replace("{author}", &field(fields.author.as_deref()))
+        .replace("{id}", &field(fields.id.as_deref()))
+        .replace("{index}", &field(index.as_deref()))
+        .replace("{name}", &field(fields.name.as_deref()))
+        .replace("{ext}", &field(Some(&fields.ext)))
+        .split('/')
+        .map(sanitize)
+        .filter(|segment| segment != "_")
+        .collect::<Vec<String>>()
+        .join("/")
+}
+
+/// Replaces the characters other than letters, digits, `-`, `_` and `.` with `_`, and strips
+/// the leading and trailing dots so that the segment is neither hidden nor `..`. Long segments
+/// are truncated before their extensions, which are kept.
+pub fn sanitize(segment: &str) -> String {
+    let sanitized = segment
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' || c == '_' || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    let sanitized = match sanitized.rsplit_once('.') {
+        _ if sanitized.chars().count() <= MAX_SEGMENT_LENGTH => sanitized,
+        Some((stem, ext)) if is_extension(ext) => format!(
+            "{}.{}",
+            stem.chars()
+                .take(MAX_SEGMENT_LENGTH - ext.len() - 1)
+                .collect::<String>(),
+            ext
+        ),
+        _ => sanitized.chars().take(MAX_SEGMENT_LENGTH).collect(),
+    };
+    let sanitized = sanitized.trim_matches('.');
+    if sanitized.is_empty() {
+        "_".to_string()
+    } else {
+        sanitized.to_string()
+    }
+}
+
+fn extension_of_content_type(content_type: &str) -> Option<&'static str> {
+    let mime_type = content_type.split(';').next()?.trim().to_lowercase();
+    Some(match mime_type.as_str() {
+        "image/jpeg" | "image/jpg" => "jpg",
+        "image/png" => "png",
+        "image/gif" => "gif",
+        "image/webp" => "webp",
+        "image/bmp" => "bmp",
+        "video/mp4" => "mp4",
+        "video/webm" => "webm",
+        "video/quicktime" => "mov",
+        "video/x-matroska" => "mkv",
+        "video/x-flv" => "flv",
+        "video/mp2t" => "ts",
+        _ => return None,
+    })
+}
+
+fn is_extension(ext: &str) -> bool {
+    (1..=5).contains(&ext.len()) && ext.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+lazy_static! {
+    /// Locks of the urls being downloaded, as the downloads of a url share its claimed path.
+    static ref DOWNLOADING: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>> =
+        Mutex::new(HashMap::new());
+}
+
+/// A path claimed for the download of a url, held for the length of the download so that
+/// another download of the url waits for it, and then finds the file downloaded.
+///
+/// Unless it is kept, the claim is released when it is dropped, e.g. as the download fails or
+/// its job is cancelled, along with the partially downloaded file.
+pub struct Claim {
+    path: String,
+    relative: String,
+    url: String,
+    kept: bool,
+    _lock: OwnedMutexGuard<()>,
+}
+
+impl Claim {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Keeps the claim of the downloaded file.
+    pub fn keep(mut self) {
+        self.kept = true;
+    }
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        if !self.kept {
+            let part_path = format!("{}.part", self.path);
+            for path in [format!("{}.validator", part_path), part_path] {
+                if let Err(err) = std::fs::remove_file(&path) {
+                    if err.kind() != ErrorKind::NotFound {
+                        warn!("failed to remove {}: {}", path, err);
+                    }
+                }
+            }
+            // e.g. only fixing its extension fails
+            if !Path::new(&self.path).exists() {
+                let released = release_in(
+                    &STORAGE.file_names,
+                    &STORAGE.file_paths,
+                    &self.relative,
+                    &self.url,
+                );
+                if let Err(err) = released {
+                    error!("failed to release the path of {}: {:#?}", self.url, err);
+                }
+            }
+        }
+        let mut downloading = DOWNLOADING.lock().unwrap();
+        // held by the map and this claim only, i.e. no other download is waiting for it
+        if downloading
+            .get(&self.url)
+            .is_some_and(|lock| Arc::strong_count(lock) <= 2)
+        {
+            downloading.remove(&self.url);
+        }
+    }
+}
+
+/// Reserves a path under `download_path` for the file downloaded from `url`, and creates its
+/// parent directories. Waits for the running download of the same url.
+pub async fn claim(relative: &str, url: &str) -> Result<Claim> {
+    let lock = DOWNLOADING
+        .lock()
+        .unwrap()
+        .entry(url.to_string())
+        .or_default()
+        .clone();
+    let lock = lock.lock_owned().await;
+    let root = Path::new(&cfg::BOT_CONFIG.download_path);
+    let relative = claim_in(
+        &STORAGE.file_names,
+        &STORAGE.file_paths,
+        root,
+        relative,
+        url,
+    )?;
+    let path = root.join(&relative);
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    Ok(Claim {
+        path: path.to_string_lossy().to_string(),
+        relative,
+        url: url.to_string(),
+        kept: false,
+        _lock: lock,
+    })
+}
+
+/// Returns the path claimed by `url` before, so that its download is resumed or skipped.
+/// Otherwise returns `relative`, or adds a hash of `url` as its suffix when another file is
+/// there, which is replaced with a random id when it is taken as well.
+///
+/// A path is taken by a file on the disk, or by another url in `paths`, where it is reserved
+/// atomically so that the downloads of different urls never share a path, even before their
+/// files exist.
+fn claim_in(
+    claims: &Repository<str, String>,
+    paths: &Repository<str, String>,
+    root: &Path,
+    relative: &str,
+    url: &str,
+) -> Result<String> {
+    if let Some(claimed) = claims.get(url)? {
+        return Ok(claimed);
+    }
+    let candidates = [
+        relative.to_string(),
+        with_suffix(relative, &format!("{:016x}", fnv1a(url.as_bytes()))),
+    ];
+    let random = std::iter::repeat_with(|| with_suffix(relative, &nanoid!(8)));
+    for candidate in candidates.into_iter().chain(random) {
+        if root.join(&candidate).exists() || root.join(format!("{}.part", candidate)).exists() {
+            continue;
+        }
+        let reserved = paths.insert_new(&candidate, &url.to_string())?
+            // reserved by a concurrent claim of the same url
+            || paths.get(&candidate)?.as_deref() == Some(url);
+        if reserved {
+            claims.insert(url, &candidate)?;
+            return Ok(candidate);
+        }
+    }
+    unreachable!("random ids are endless")
+}
+
+/// Removes the claim of `url` on `relative`, leaving the entries taken by other urls since.
+fn release_in(
+    claims: &Repository<str, String>,
+    paths: &Repository<str, String>,
+    relative: &str,
+    url: &str,
+) -> Result<()> {
+    if paths.get(relative)?.as_deref() == Some(url) {
+        paths.remove(relative)?;
+    }
+    if claims.get(url)?.as_deref() == Some(relative) {
+        claims.remove(url)?;
+    }
+    Ok(())
+}
+
+fn with_suffix(relative: &str, suffix: &str) -> String {
+    let (dir, name) = match relative.rsplit_once('/') {
+        Some((dir, name)) => (format!("{}/", dir), name),
+        None => (String::new(), relative),
+    };
+    match name.rsplit_once('.') {
+        Some((stem, ext)) => format!("{}{}_{}.{}", dir, stem, suffix, ext),
+        None => format!("{}{}_{}", dir, name, suffix),
+    }
+}
+
+/// Stable across builds, unlike `DefaultHasher`.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Renames the downloaded file when its magic bytes tell a different extension, e.g. of a file
+/// served without Content-Type. Returns the path of the file.
+pub async fn fix_extension(path: &str, url: &str) -> Result<String> {
+    let mut head = vec![0; 8192];
+    let mut file = tokio::fs::File::open(path).await?;
+    let len = file.read(&mut head).await?;
+    head.truncate(len);
+
+    let ext = match infer::get(&head) {
+        Some(kind) => kind.extension(),
+        None => return Ok(path.to_string()),
+    };
+    let current = Path::new(path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_lowercase());
+    if current.as_deref() == Some(ext) || (ext == "jpg" && current.as_deref() == Some("jpeg")) {
+        return Ok(path.to_string());
+    }
+    let renamed = Path::new(path).with_extension(ext);
+    if renamed.exists() {
+        return Ok(path.to_string());
+    }
+    let root = Path::new(&cfg::BOT_CONFIG.download_path);
+    let relative = |path: &Path| {
+        path.strip_prefix(root)
+            .ok()
+            .map(|relative| relative.to_string_lossy().to_string())
+    };
+    if let Some(relative) = relative(&renamed) {
+        if !STORAGE.file_paths.insert_new(&relative, &url.to_string())? {
+            return Ok(path.to_string());
+        }
+        STORAGE.file_names.insert(url, &relative)?;
+    }
+    tokio::fs::rename(path, &renamed).await?;
+    if let Some(previous) = relative(Path::new(path)) {
+        STORAGE.file_paths.remove(&previous)?;
+    }
+    info!("renamed {} to {:?} by its magic bytes", path, renamed);
+    Ok(renamed.to_string_lossy().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::naming::{claim_in, release_in, render, sanitize, with_suffix, NameFields};
+    use crate::storage::Repository;
+
+    #[test]
+    fn sanitize_test() {
+        assert_eq!(sanitize("video.mp4"), "video.mp4");
+        assert_eq!(sanitize("初音ミク 01.png"), "初音ミク_01.png");
+        assert_eq!(sanitize("a/b\\c:d?.mp4"), "a_b_c_d_.mp4");
+        assert_eq!(sanitize(".."), "_");
+        assert_eq!(sanitize(".hidden."), "hidden");
+        assert_eq!(sanitize(&"a".repeat(200)).len(), 100);
+        assert_eq!(
+            sanitize(&format!("{}.mp4", "a".repeat(200))),
+            format!("{}.mp4", "a".repeat(96))
+        );
+    }
+
+    #[test]
+    fn render_test() {
+        let fields = NameFields {
+            site: Some("pixiv".to_string()),
+            author: Some("some/artist".to_string()),
+            id: Some("99118150".to_string()),
+            index: Some(0),
+            ext: "png".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            render("{site}/{author}/{id}_{index}.{ext}", &fields),
+            "pixiv/some_artist/99118150_0.png"
+        );
+        assert_eq!(render("../{name}.{ext}", &fields), "unknown.png");
+        assert_eq!(render("/{site}//{id}.{ext}", &fields), "pixiv/99118150.png");
+
+        let fields = NameFields {
+            name: Some("长".repeat(150)),
+            ext: "mp4".to_string(),
+            ..Default::default()
+        };
+        let rendered = render("videos/{name}.{ext}", &fields);
+        assert_eq!(rendered, format!("videos/{}.mp4", "长".repeat(96)));
+    }
+
+    #[test]
+    fn claim_test() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let claims: Repository<str, String> = Repository::open(&db, "file_names").unwrap();
+        let paths: Repository<str, String> = Repository::open(&db, "file_paths").unwrap();
+        let root = std::env::temp_dir().join(format!("naming_{}", std::process::id()));
+        std::fs::create_dir_all(root.join("site")).unwrap();
+
+        let url_a = "https://site/a/video.mp4";
+        let url_b = "https://site/b/video.mp4";
+        let url_c = "https://site/c/video.mp4";
+        assert_eq!(
+            claim_in(&claims, &paths, &root, "site/video.mp4", url_a).unwrap(),
+            "site/video.mp4"
+        );
+        // the path is taken before its file is created
+        let claimed_b = claim_in(&claims, &paths, &root, "site/video.mp4", url_b).unwrap();
+        assert!(claimed_b.starts_with("site/video_") && claimed_b.ends_with(".mp4"));
+        std::fs::write(root.join("site/video.mp4"), b"a").unwrap();
+        // the same url gets the same path, so that the download can be skipped
+        assert_eq!(
+            claim_in(&claims, &paths, &root, "site/video.mp4", url_a).unwrap(),
+            "site/video.mp4"
+        );
+        assert_eq!(
+            claim_in(&claims, &paths, &root, "site/video.mp4", url_b).unwrap(),
+            claimed_b
+        );
+        let claimed_c = claim_in(&claims, &paths, &root, "site/video.mp4", url_c).unwrap();
+        assert!(claimed_c.starts_with("site/video_") && claimed_c.ends_with(".mp4"));
+        assert_ne!(claimed_c, claimed_b);
+        assert_eq!(paths.get(&claimed_c).unwrap().as_deref(), Some(url_c));
+
+        // a released path is claimed again, by the same url or another one
+        release_in(&claims, &paths, &claimed_c, url_c).unwrap();
+        assert_eq!(claims.get(url_c).unwrap(), None);
+        assert_eq!(paths.get(&claimed_c).unwrap(), None);
+        release_in(&claims, &paths, &claimed_b, url_c).unwrap();
+        assert_eq!(paths.get(&claimed_b).unwrap().as_deref(), Some(url_b));
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn with_suffix_test() {
+        assert_eq!(with_suffix("a/b/c.mp4", "1"), "a/b/c_1.mp4");
+        assert_eq!(with_suffix("c", "1"), "c_1");
+    }
+}
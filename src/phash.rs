@@ -0,0 +1,112 @@
+use anyhow::Result;
+use image::imageops::FilterType;
+use image::DynamicImage;
+
+/// Perceptual hashes of an image, similar images have hashes with a small hamming distance.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ImageHash {
+    pub dhash: u64,
+    pub phash: u64,
+}
+
+pub fn hash_image(image: &[u8]) -> Result<ImageHash> {
+    let image = image::load_from_memory(image)?;
+    Ok(ImageHash {
+        dhash: dhash(&image),
+        phash: phash(&image),
+    })
+}
+
+pub fn hamming_distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// Compares the brightness of horizontally adjacent pixels of a 9x8 thumbnail.
+pub fn dhash(image: &DynamicImage) -> u64 {
+    let image = image.resize_exact(9, 8, FilterType::Triangle).into_luma8();
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            let left = image.get_pixel(x, y).0[0];
+            let right = image.get_pixel(x + 1, y).0[0];
+            hash = (hash << 1) | (left < right) as u64;
+        }
+    }
+    hash
+}
+
+/// Compares the low frequency DCT coefficients of a 32x32 thumbnail with their median.
+pub fn phash(image: &DynamicImage) -> u64 {
+    const SIZE: usize = 32;
+    let image = image
+        .resize_exact(SIZE as u32, SIZE as u32, FilterType::Triangle)
+        .into_luma8();
+    let pixels: Vec<f64> = image.pixels().map(|pixel| pixel.0[0] as f64).collect();
+
+    let cosines: Vec<f64> = (0..SIZE * SIZE)
+        .map(|i| {
+            let (u, x) = ((i / SIZE) as f64, (i % SIZE) as f64);
+            ((2.0 * x + 1.0) * u * std::f64::consts::PI / (2.0 * SIZE as f64)).cos()
+        })
+        .collect();
+    // the DCT is separable, transform the rows first and then the columns
+    let mut rows = vec![0f64; SIZE * SIZE];
+    for y in 0..SIZE {
+        for u in 0..8 {
+            rows[y * SIZE + u] = (0..SIZE)
+                .map(|x| pixels[y * SIZE + x] * cosines[u * SIZE + x])
+                .sum();
+        }
+    }
+    let mut coefficients = [0f64; 64];
+    for v in 0..8 {
+        for u in 0..8 {
+            coefficients[v * 8 + u] = (0..SIZE)
+                .map(|y| rows[y * SIZE + u] * cosines[v * SIZE + y])
+                .sum();
+        }
+    }
+
+    // the DC coefficient only reflects the average brightness
+    let mut sorted = coefficients[1..].to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let median = sorted[sorted.len() / 2];
+    coefficients.iter().fold(0u64, |hash, coefficient| {
+        (hash << 1) | (*coefficient > median) as u64
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::phash::{dhash, hamming_distance, phash};
+    use image::{DynamicImage, RgbImage};
+
+    fn gradient(width: u32, height: u32, invert: bool) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+            let value = ((x * 255 / width + y * 64 / height) % 256) as u8;
+            let value = if invert { 255 - value } else { value };
+            image::Rgb([value, value / 2, 255 - value / 3])
+        }))
+    }
+
+    #[test]
+    fn similar_images_test() {
+        let original = gradient(400, 300, false);
+        let resized = original.resize_exact(200, 150, image::imageops::FilterType::Nearest);
+        let recompressed = DynamicImage::ImageRgb8(original.blur(1.0).to_rgb8());
+
+        assert!(hamming_distance(dhash(&original), dhash(&resized)) <= 3);
+        assert!(hamming_distance(phash(&original), phash(&resized)) <= 8);
+        assert!(hamming_distance(dhash(&original), dhash(&recompressed)) <= 3);
+        assert!(hamming_distance(phash(&original), phash(&recompressed)) <= 8);
+    }
+
+    #[test]
+    fn different_images_test() {
+        let original = gradient(400, 300, false);
+        let inverted = gradient(400, 300, true);
+
+        assert!(hamming_distance(dhash(&original), dhash(&inverted)) > 16);
+        assert!(hamming_distance(phash(&original), phash(&inverted)) > 16);
+    }
+}
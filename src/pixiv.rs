@@ -0,0 +1,345 @@
+use crate::aggregator::AggregatedImage;
+use crate::client::CLIENT;
+use crate::message::*;
+use crate::naming::{self, NameFields};
+use crate::{cfg, local_search, utils};
+use anyhow::{bail, Result};
+use lazy_static::lazy_static;
+use log::error;
+use regex::Regex;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+static PIXIV_BASE_URL: &str = "https://www.pixiv.net";
+
+/// The body is parsed after the error is checked, as it is `[]` along with an error.
+#[derive(Deserialize, Debug)]
+struct PixivResponse {
+    error: bool,
+    message: String,
+    #[serde(default)]
+    body: serde_json::Value,
+}
+
+impl PixivResponse {
+    fn into_body<T: DeserializeOwned>(self) -> Result<T> {
+        if self.error {
+            bail!("pixiv returns an error: {}", self.message);
+        }
+        Ok(serde_json::from_value(self.body)?)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PixivIllust {
+    pub illust_id: String,
+    pub illust_title: String,
+    pub user_name: String,
+    pub tags: PixivIllustTags,
+    pub page_count: u32,
+    pub bookmark_count: u32,
+    /// 0 for all-ages works, 1 for R-18 and 2 for R-18G.
+    pub x_restrict: u8,
+    pub create_date: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PixivIllustTags {
+    pub tags: Vec<PixivIllustTag>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PixivIllustTag {
+    pub tag: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PixivPage {
+    pub urls: PixivPageUrls,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PixivPageUrls {
+    pub original: String,
+}
+
+impl PixivIllust {
+    pub fn is_r18(&self) -> bool {
+        self.x_restrict > 0
+    }
+
+    pub fn to_metadata(&self) -> HashMap<String, String> {
+        HashMap::from([
+            ("标题".to_string(), self.illust_title.clone()),
+            ("作者".to_string(), self.user_name.clone()),
+            (
+                "标签".to_string(),
+                self.tags
+                    .tags
+                    .iter()
+                    .map(|tag| tag.tag.as_str())
+                    .collect::<Vec<&str>>()
+                    .join(", "),
+            ),
+            ("页数".to_string(), self.page_count.to_string()),
+            ("收藏".to_string(), self.bookmark_count.to_string()),
+            (
+                "R-18".to_string(),
+                if self.is_r18() { "是" } else { "否" }.to_string(),
+            ),
+            (
+                "发布时间".to_string(),
+                self.create_date.replacen('T', " ", 1),
+            ),
+        ])
+    }
+}
+
+pub async fn on_group_message(message: OneBotGroupMessage) -> Vec<BotResponseAction> {
+    lazy_static! {
+        static ref ARTWORK_URL_REGEX: Regex =
+            Regex::new(r"https?://(?:www\.)?pixiv\.net/(?:en/)?artworks/(\d+)").unwrap();
+    }
+
+    let OneBotGroupMessage {
+        message,
+        message_id,
+        group_id,
+        self_id,
+        ..
+    } = message;
+    let group = match cfg::BOT_CONFIG
+        .pixiv
+        .groups
+        .iter()
+        .find(|group| group.group_id == group_id)
+    {
+        Some(group) => group,
+        None => return vec![],
+    };
+    let id = match ARTWORK_URL_REGEX.captures(&message) {
+        Some(caps) => caps[1].to_string(),
+        None => return vec![],
+    };
+
+    let reply = |message: String| BotResponseAction::GroupMessage {
+        group_id,
+        message: format!("[CQ:reply,id={}]{}", message_id, message),
+    };
+    let phpsessid = cfg::BOT_CONFIG.pixiv.phpsessid.as_deref();
+    let illust = match fetch_illust(PIXIV_BASE_URL, &id, phpsessid).await {
+        Ok(illust) => illust,
+        Err(err) => return vec![reply(format!("获取作品信息时出错: {:#?}", err))],
+    };
+    let mut info = format!(
+        "{}\nhttps://www.pixiv.net/artworks/{}",
+        utils::serialize_hashmap(&illust.to_metadata()),
+        illust.illust_id
+    );
+    if illust.is_r18() && !group.allow_r18 {
+        info.push_str("\n本群不发送 R-18 作品");
+        return vec![reply(info)];
+    }
+    if illust.page_count as usize > group.max_pages {
+        info.push_str(
+            format!(
+                "\n共 {} 页，仅发送前 {} 页",
+                illust.page_count, group.max_pages
+            )
+            .as_str(),
+        );
+    }
+
+    let images = match fetch_page_images(&id, &illust.user_name, phpsessid, group.max_pages).await {
+        Ok(images) => images,
+        Err(err) => {
+            info.push_str(format!("\n获取图片时出错: {:#?}", err).as_str());
+            return vec![reply(info)];
+        }
+    };
+    match images.as_slice() {
+        [] => vec![reply(info)],
+        [image] => vec![reply(format!("{}\n{}", info, image))],
+        _ => vec![
+            reply(info),
+            BotResponseAction::GroupForwardMessage {
+                group_id,
+                messages: images
+                    .into_iter()
+                    .map(|content| ForwardMessageNode::Node {
+                        name: illust.user_name.clone(),
+                        uin: self_id,
+                        content,
+                    })
+                    .collect(),
+            },
+        ],
+    }
+}
+
+/// Returns the pages of an artwork as CQ image codes.
+async fn fetch_page_images(
+    id: &str,
+    author: &str,
+    phpsessid: Option<&str>,
+    max_pages: usize,
+) -> Result<Vec<String>> {
+    let pages = fetch_pages(PIXIV_BASE_URL, id, phpsessid).await?;
+    let mut images = vec![];
+    for (index, page) in pages.into_iter().take(max_pages).enumerate() {
+        let image = match &cfg::BOT_CONFIG.pixiv.mirror {
+            Some(mirror) => to_mirror_url(&page.urls.original, mirror),
+            None => {
+                let path = download_page(&page.urls.original, id, author, index).await?;
+                format!("file://{}", path)
+            }
+        };
+        images.push(format!("[CQ:image,file={}]", image));
+    }
+    Ok(images)
+}
+
+fn to_mirror_url(url: &str, mirror: &str) -> String {
+    url.replacen("i.pximg.net", mirror, 1)
+}
+
+async fn download_page(url: &str, id: &str, author: &str, index: usize) -> Result<String> {
+    let request = CLIENT
+        .get(url)
+        .header(reqwest::header::REFERER, "https://www.pixiv.net/");
+    let response = utils::send_cloned(&request).await?;
+    let fields = NameFields {
+        author: Some(author.to_string()),
+        id: Some(id.to_string()),
+        index: Some(index),
+        ..NameFields::from_response(&response, "jpg")
+    };
+    let claim = naming::claim(&naming::render(&cfg::BOT_CONFIG.naming.pixiv, &fields), url).await?;
+    utils::download_file_if_not_exists(response, request, claim.path(), None, None).await?;
+    let path = naming::fix_extension(claim.path(), url).await?;
+    claim.keep();
+    local_search::spawn_index_file(path.clone());
+    Ok(path)
+}
+
+/// Adds the details of pixiv artworks to their metadata.
+pub async fn enrich(images: &mut [AggregatedImage]) {
+    let tasks = images.iter_mut().filter_map(|image| {
+        let id = utils::extract_pixiv_artwork_id(&image.url)?;
+        Some(async move {
+            match fetch_illust(
+                PIXIV_BASE_URL,
+                &id,
+                cfg::BOT_CONFIG.pixiv.phpsessid.as_deref(),
+            )
+            .await
+            {
+                Ok(illust) => image.metadata.extend(illust.to_metadata()),
+                Err(err) => error!("failed to fetch pixiv artwork {}: {:#?}", id, err),
+            }
+        })
+    });
+    futures::future::join_all(tasks).await;
+}
+
+pub async fn fetch_illust(
+    base_url: &str,
+    id: &str,
+    phpsessid: Option<&str>,
+) -> Result<PixivIllust> {
+    let mut request = CLIENT
+        .get(format!("{}/ajax/illust/{}", base_url, id))
+        .header(reqwest::header::REFERER, "https://www.pixiv.net/");
+    if let Some(phpsessid) = phpsessid {
+        request = request.header(reqwest::header::COOKIE, format!("PHPSESSID={}", phpsessid));
+    }
+    // pixiv responds with 4xx along with an error message for deleted or restricted works
+    let response: PixivResponse = request.send().await?.json().await?;
+    response.into_body()
+}
+
+pub async fn fetch_pages(
+    base_url: &str,
+    id: &str,
+    phpsessid: Option<&str>,
+) -> Result<Vec<PixivPage>> {
+    let mut request = CLIENT
+        .get(format!("{}/ajax/illust/{}/pages", base_url, id))
+        .header(reqwest::header::REFERER, "https://www.pixiv.net/");
+    if let Some(phpsessid) = phpsessid {
+        request = request.header(reqwest::header::COOKIE, format!("PHPSESSID={}", phpsessid));
+    }
+    let response: PixivResponse = request.send().await?.json().await?;
+    response.into_body()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pixiv::{fetch_illust, fetch_pages, to_mirror_url};
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[tokio::test]
+    async fn fetch_illust_test() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/ajax/illust/99118150"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                r###"{"error":false,"message":"","body":{"illustId":"99118150","illustTitle":"夏","illustType":0,"userId":"1234","userName":"foo","tags":{"authorId":"1234","isLocked":false,"tags":[{"tag":"オリジナル","locked":true,"deletable":false,"userId":"1234","translation":{"en":"original"}},{"tag":"女の子","locked":true,"deletable":false}]},"pageCount":3,"bookmarkCount":4321,"likeCount":2000,"viewCount":30000,"xRestrict":0,"createDate":"2022-06-25T10:00:00+00:00","uploadDate":"2022-06-25T10:00:00+00:00"}}"###,
+            ))
+            .mount(&server)
+            .await;
+
+        let illust = fetch_illust(&server.uri(), "99118150", None).await.unwrap();
+        assert!(!illust.is_r18());
+        let metadata = illust.to_metadata();
+        assert_eq!(metadata["标题"], "夏");
+        assert_eq!(metadata["作者"], "foo");
+        assert_eq!(metadata["标签"], "オリジナル, 女の子");
+        assert_eq!(metadata["页数"], "3");
+        assert_eq!(metadata["收藏"], "4321");
+        assert_eq!(metadata["R-18"], "否");
+        assert_eq!(metadata["发布时间"], "2022-06-25 10:00:00+00:00");
+    }
+
+    #[tokio::test]
+    async fn fetch_deleted_illust_test() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/ajax/illust/1"))
+            .respond_with(ResponseTemplate::new(404).set_body_string(
+                r###"{"error":true,"message":"该作品已被删除，或作品ID不存在。","body":[]}"###,
+            ))
+            .mount(&server)
+            .await;
+
+        assert_eq!(
+            fetch_illust(&server.uri(), "1", Some("foo"))
+                .await
+                .unwrap_err()
+                .to_string(),
+            "pixiv returns an error: 该作品已被删除，或作品ID不存在。"
+        );
+    }
+
+    #[tokio::test]
+    async fn fetch_pages_test() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/ajax/illust/99118150/pages"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                r###"{"error":false,"message":"","body":[{"urls":{"thumb_mini":"https://i.pximg.net/c/128x128/img-master/img/2022/06/25/19/00/00/99118150_p0_square1200.jpg","small":"https://i.pximg.net/c/540x540_70/img-master/img/2022/06/25/19/00/00/99118150_p0_master1200.jpg","regular":"https://i.pximg.net/img-master/img/2022/06/25/19/00/00/99118150_p0_master1200.jpg","original":"https://i.pximg.net/img-original/img/2022/06/25/19/00/00/99118150_p0.png"},"width":2000,"height":3000},{"urls":{"thumb_mini":"","small":"","regular":"","original":"https://i.pximg.net/img-original/img/2022/06/25/19/00/00/99118150_p1.png"},"width":2000,"height":3000}]}"###,
+            ))
+            .mount(&server)
+            .await;
+
+        let pages = fetch_pages(&server.uri(), "99118150", None).await.unwrap();
+        assert_eq!(pages.len(), 2);
+        assert_eq!(
+            to_mirror_url(&pages[1].urls.original, "i.pixiv.re"),
+            "https://i.pixiv.re/img-original/img/2022/06/25/19/00/00/99118150_p1.png"
+        );
+    }
+}
@@ -0,0 +1,286 @@
+use crate::database::*;
+use crate::message::*;
+use crate::phash::{hamming_distance, ImageHash};
+use crate::{cfg, local_search, phash, searcher, sender, utils};
+use anyhow::Result;
+use lazy_static::lazy_static;
+use log::{debug, error};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+lazy_static! {
+    static ref IMAGE_URL_REGEX: Regex = Regex::new(r"url=([^]]+)]").unwrap();
+}
+
+/// The dHash is split into 4 chunks of 16 bits, so any hash within a distance of 3 shares
+/// at least one chunk with the query.
+const CHUNKS: usize = 4;
+/// The maximum distance within which the index finds every match.
+pub const MAX_DISTANCE: u32 = CHUNKS as u32 - 1;
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct RepostRecord {
+    pub user_id: i64,
+    pub message_id: i32,
+    pub time: u64,
+    pub dhash: u64,
+    pub phash: u64,
+}
+
+/// Perceptual hashes of the images posted in a group, stored in the tree `repost:{group_id}`.
+///
+/// Records are stored under `r{message_id}{dhash}`, and each of them is indexed under
+/// `i{chunk index}{chunk}{message_id}{dhash}` for every chunk of its dHash.
+pub struct RepostIndex {
+    tree: sled::Tree,
+}
+
+impl RepostIndex {
+    pub fn open(db: &sled::Db, group_id: i64) -> Result<Self> {
+        Ok(RepostIndex {
+            tree: db.open_tree(format!("repost:{}", group_id))?,
+        })
+    }
+
+    pub fn insert(&self, record: &RepostRecord) -> Result<()> {
+        let suffix = [
+            &record.message_id.to_be_bytes()[..],
+            &record.dhash.to_be_bytes()[..],
+        ]
+        .concat();
+        let mut batch = sled::Batch::default();
+        batch.insert([b"r", &suffix[..]].concat(), serde_json::to_vec(record)?);
+        for (i, chunk) in chunks(record.dhash).iter().enumerate() {
+            batch.insert(
+                [b"i", &[i as u8][..], &chunk.to_be_bytes()[..], &suffix[..]].concat(),
+                vec![],
+            );
+        }
+        self.tree.apply_batch(batch)?;
+        Ok(())
+    }
+
+    /// Returns the records whose dHash is within `max_distance` (at most 3) of the given hash
+    /// and whose pHash is within `max_phash_distance`, the earliest one first.
+    pub fn find(
+        &self,
+        hash: &ImageHash,
+        max_distance: u32,
+        max_phash_distance: u32,
+    ) -> Result<Vec<RepostRecord>> {
+        let mut records: Vec<RepostRecord> = vec![];
+        for (i, chunk) in chunks(hash.dhash).iter().enumerate() {
+            let prefix = [b"i", &[i as u8][..], &chunk.to_be_bytes()[..]].concat();
+            for item in self.tree.scan_prefix(&prefix) {
+                let (key, _) = item?;
+                let value = match self.tree.get([b"r", &key[prefix.len()..]].concat())? {
+                    Some(value) => value,
+                    None => continue,
+                };
+                let record: RepostRecord = serde_json::from_slice(&value)?;
+                if hamming_distance(record.dhash, hash.dhash) <= max_distance
+                    && hamming_distance(record.phash, hash.phash) <= max_phash_distance
+                    && !records.contains(&record)
+                {
+                    records.push(record);
+                }
+            }
+        }
+        records.sort_by_key(|record| (record.time, record.message_id));
+        Ok(records)
+    }
+
+    pub fn records(&self) -> Result<Vec<RepostRecord>> {
+        self.tree
+            .scan_prefix(b"r")
+            .map(|item| Ok(serde_json::from_slice(&item?.1)?))
+            .collect()
+    }
+}
+
+/// Returns the groups which have images indexed.
+pub fn indexed_groups(db: &sled::Db) -> Vec<i64> {
+    db.tree_names()
+        .iter()
+        .filter_map(|name| {
+            std::str::from_utf8(name)
+                .ok()?
+                .strip_prefix("repost:")?
+                .parse()
+                .ok()
+        })
+        .collect()
+}
+
+fn chunks(hash: u64) -> [u16; CHUNKS] {
+    let mut chunks = [0; CHUNKS];
+    for (i, chunk) in chunks.iter_mut().enumerate() {
+        *chunk = (hash >> (48 - i * 16)) as u16;
+    }
+    chunks
+}
+
+pub async fn on_group_message(message: OneBotGroupMessage) -> Option<BotResponseAction> {
+    let OneBotGroupMessage {
+        message,
+        message_id,
+        group_id,
+        user_id,
+        ..
+    } = message;
+    if !cfg::BOT_CONFIG.repost.groups.contains(&group_id) {
+        return None;
+    }
+
+    // hashing takes a while, so the reply is sent in the background
+    for caps in IMAGE_URL_REGEX.captures_iter(&message) {
+        let url = caps[1].to_string();
+        tokio::spawn(async move {
+            match check_repost(&url, group_id, user_id, message_id).await {
+                Ok(Some(reply)) => sender::send(reply).await,
+                Ok(None) => {}
+                Err(err) => error!("failed to check repost of {}: {:#?}", url, err),
+            }
+        });
+    }
+    None
+}
+
+async fn check_repost(
+    url: &str,
+    group_id: i64,
+    user_id: i64,
+    message_id: i32,
+) -> Result<Option<BotResponseAction>> {
+    let image = searcher::fetch_image(url).await?;
+    let hash = tokio::task::spawn_blocking(move || phash::hash_image(&image)).await??;
+
+    let config = &cfg::BOT_CONFIG.repost;
+    let index = RepostIndex::open(&DATABASE, group_id)?;
+    let original = index
+        .find(&hash, config.max_distance, config.max_phash_distance)?
+        .into_iter()
+        .find(|record| record.message_id != message_id);
+    let original = match original {
+        Some(original) => original,
+        None => {
+            let record = RepostRecord {
+                user_id,
+                message_id,
+                time: utils::unix_timestamp(),
+                dhash: hash.dhash,
+                phash: hash.phash,
+            };
+            index.insert(&record)?;
+            local_search::index_group_image(group_id, &record);
+            return Ok(None);
+        }
+    };
+
+    debug!(
+        "image of message {} in group {} is a repost of message {}",
+        message_id, group_id, original.message_id
+    );
+    Ok(Some(BotResponseAction::GroupMessage {
+        group_id,
+        message: format!(
+            "[CQ:reply,id={}]这张图 [CQ:at,qq={}] 在 {} 发过了",
+            message_id,
+            original.user_id,
+            utils::format_timestamp(original.time)
+        ),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cfg::RepostConfig;
+    use crate::phash::ImageHash;
+    use crate::repost::{RepostIndex, RepostRecord};
+
+    #[test]
+    fn repost_index_test() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let index = RepostIndex::open(&db, 1).unwrap();
+        let dhash = 0x0123_4567_89ab_cdef;
+        let phash = 0xfedc_ba98_7654_3210;
+        index
+            .insert(&RepostRecord {
+                user_id: 10000,
+                message_id: 2,
+                time: 200,
+                dhash: dhash ^ 0b1,
+                phash,
+            })
+            .unwrap();
+        index
+            .insert(&RepostRecord {
+                user_id: 10000,
+                message_id: 1,
+                time: 100,
+                dhash,
+                phash: phash ^ 0b11,
+            })
+            .unwrap();
+        // only shares the last chunk
+        index
+            .insert(&RepostRecord {
+                user_id: 10000,
+                message_id: 3,
+                time: 50,
+                dhash: dhash ^ 0x0001_0001_0001_0000,
+                phash,
+            })
+            .unwrap();
+        index
+            .insert(&RepostRecord {
+                user_id: 10000,
+                message_id: 5,
+                time: 20,
+                dhash: dhash ^ 0x0001_0001_0001_0001,
+                phash,
+            })
+            .unwrap();
+        index
+            .insert(&RepostRecord {
+                user_id: 10000,
+                message_id: 4,
+                time: 10,
+                dhash: !dhash,
+                phash,
+            })
+            .unwrap();
+
+        let found = index.find(&ImageHash { dhash, phash }, 3, 8).unwrap();
+        assert_eq!(
+            found
+                .iter()
+                .map(|record| record.message_id)
+                .collect::<Vec<i32>>(),
+            vec![3, 1, 2]
+        );
+        assert!(index
+            .find(
+                &ImageHash {
+                    dhash,
+                    phash: !phash
+                },
+                3,
+                8
+            )
+            .unwrap()
+            .is_empty());
+        assert!(RepostIndex::open(&db, 2)
+            .unwrap()
+            .find(&ImageHash { dhash, phash }, 3, 8)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn max_distance_config_test() {
+        let config: RepostConfig = serde_json::from_str(r#"{"max_distance":2}"#).unwrap();
+        assert_eq!(config.max_distance, 2);
+        assert!(serde_json::from_str::<RepostConfig>(r#"{"max_distance":4}"#).is_err());
+    }
+}
@@ -0,0 +1,221 @@
+use crate::database::*;
+use crate::message::*;
+use crate::{cfg, utils};
+use anyhow::Result;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Values of the default tree along with the time they are inserted.
+#[derive(Debug, Serialize, Deserialize)]
+struct TimestampedValue {
+    time: u64,
+    value: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Timestamp {
+    time: u64,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct PrefixStats {
+    pub count: usize,
+    pub bytes: u64,
+}
+
+/// Values written before timestamps were stored are treated as inserted at `default_time`.
+pub fn decode(value: &[u8], default_time: u64) -> (u64, String) {
+    match serde_json::from_slice::<TimestampedValue>(value) {
+        Ok(TimestampedValue { time, value }) => (time, value),
+        Err(_) => (default_time, String::from_utf8_lossy(value).to_string()),
+    }
+}
+
+fn prefix_of(key: &str) -> &str {
+    key.split(':').next().unwrap_or(key)
+}
+
+/// Removes the values of the tree which are JSON objects with a `time` older than the TTL.
+pub fn sweep_tree(tree: &sled::Tree, ttl: u64, now: u64) -> Result<usize> {
+    let mut removed = 0;
+    for item in tree.iter() {
+        let (key, value) = item?;
+        let time = match serde_json::from_slice::<Timestamp>(&value) {
+            Ok(Timestamp { time }) => time,
+            Err(_) => continue,
+        };
+        if time.saturating_add(ttl) < now {
+            tree.remove(&key)?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Counts the keys and their sizes by the prefixes of the keys in the default tree, and by
+/// the prefixes of the names of other trees, e.g. `repost` for `repost:{group_id}`.
+pub fn stats(db: &sled::Db) -> Result<BTreeMap<String, PrefixStats>> {
+    let mut stats: BTreeMap<String, PrefixStats> = BTreeMap::new();
+    for name in db.tree_names() {
+        let name = String::from_utf8_lossy(&name).to_string();
+        let is_default = name == "__sled__default";
+        let tree = db.open_tree(&name)?;
+        for item in tree.iter() {
+            let (key, value) = item?;
+            let prefix = if is_default {
+                prefix_of(&String::from_utf8_lossy(&key)).to_string()
+            } else {
+                format!("[{}]", prefix_of(&name))
+            };
+            let entry = stats.entry(prefix).or_default();
+            entry.count += 1;
+            entry.bytes += (key.len() + value.len()) as u64;
+        }
+    }
+    Ok(stats)
+}
+
+pub fn spawn_sweeper() {
+    tokio::spawn(async {
+        let config = &cfg::BOT_CONFIG.retention;
+        let mut interval = tokio::time::interval(Duration::from_secs(config.sweep_interval_secs));
+        loop {
+            interval.tick().await;
+            let result = tokio::task::spawn_blocking(|| -> Result<usize> {
+                let now = utils::unix_timestamp();
+                let mut removed = 0;
+                for (name, ttl) in &config.tree_ttls {
+                    removed += sweep_tree(&DATABASE.open_tree(name)?, *ttl, now)?;
+                }
+                removed += sweep_tree(
+                    &DATABASE.open_tree("search_cache")?,
+                    cfg::BOT_CONFIG.search_cache_ttl_secs,
+                    now,
+                )?;
+                Ok(removed)
+            })
+            .await;
+            match result {
+                Ok(Ok(0)) => {}
+                Ok(Ok(removed)) => info!("removed {} expired keys from the database", removed),
+                Ok(Err(err)) => error!("failed to sweep the database: {:#?}", err),
+                Err(err) => error!("failed to sweep the database: {:#?}", err),
+            }
+        }
+    });
+}
+
+pub async fn on_group_message(message: OneBotGroupMessage) -> Option<BotResponseAction> {
+    let OneBotGroupMessage {
+        message,
+        user_id,
+        group_id,
+        ..
+    } = message;
+    if user_id != cfg::BOT_CONFIG.admin_user_id || message.trim() != "db-stats" {
+        return None;
+    }
+
+    Some(BotResponseAction::GroupMessage {
+        group_id,
+        message: format_stats(),
+    })
+}
+
+pub async fn on_private_message(message: OneBotPrivateMessage) -> Option<BotResponseAction> {
+    let OneBotPrivateMessage {
+        user_id, message, ..
+    } = message;
+    if user_id != cfg::BOT_CONFIG.admin_user_id || message.trim() != "db-stats" {
+        return None;
+    }
+
+    Some(BotResponseAction::PrivateMessage {
+        user_id,
+        message: format_stats(),
+    })
+}
+
+fn format_stats() -> String {
+    let stats = match stats(&DATABASE) {
+        Ok(stats) => stats,
+        Err(err) => return format!("统计数据库时出错: {:#?}", err),
+    };
+    let size_on_disk = DATABASE
+        .size_on_disk()
+        .map(|size| human_bytes::human_bytes(size as f64))
+        .unwrap_or_else(|_| "未知".to_string());
+    stats.iter().fold(
+        format!("数据库占用 {}", size_on_disk),
+        |result, (prefix, stats)| {
+            let config = &cfg::BOT_CONFIG.retention;
+            let ttl = match prefix
+                .strip_prefix('[')
+                .and_then(|name| name.strip_suffix(']'))
+                .and_then(|name| config.tree_ttls.get(name))
+            {
+                Some(ttl) => format!("，保留 {} 秒", ttl),
+                None => "".to_string(),
+            };
+            format!(
+                "{}\n{}: {} 条，{}{}",
+                result,
+                prefix,
+                stats.count,
+                human_bytes::human_bytes(stats.bytes as f64),
+                ttl
+            )
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::retention::{decode, stats, sweep_tree, PrefixStats};
+
+    #[test]
+    fn decode_test() {
+        assert_eq!(
+            decode(br#"{"time":1000,"value":"a"}"#, 2000),
+            (1000, "a".to_string())
+        );
+        assert_eq!(
+            decode(b"https://example.com/3", 2000),
+            (2000, "https://example.com/3".to_string())
+        );
+    }
+
+    #[test]
+    fn sweep_tree_test() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = db.open_tree("messages").unwrap();
+        tree.insert("1", r#"{"url":"a","time":1000}"#).unwrap();
+        tree.insert("2", r#"{"url":"b","time":1200}"#).unwrap();
+        tree.insert("3", "malformed").unwrap();
+
+        assert_eq!(sweep_tree(&tree, 100, 1250).unwrap(), 1);
+        assert!(!tree.contains_key("1").unwrap());
+        assert_eq!(tree.len(), 2);
+    }
+
+    #[test]
+    fn stats_test() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        db.insert("image_url:1", "a").unwrap();
+        db.insert("image_url:22", "b").unwrap();
+        db.open_tree("repost:1").unwrap().insert("k", "v").unwrap();
+        db.open_tree("repost:2").unwrap().insert("k", "v").unwrap();
+
+        let stats = stats(&db).unwrap();
+        assert_eq!(
+            stats["image_url"],
+            PrefixStats {
+                count: 2,
+                bytes: 25
+            }
+        );
+        assert_eq!(stats["[repost]"], PrefixStats { count: 2, bytes: 4 });
+    }
+}
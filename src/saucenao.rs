@@ -1,11 +1,14 @@
+use crate::cfg;
 use crate::client::CLIENT;
 use crate::searcher::*;
 use async_trait::async_trait;
+use reqwest::multipart::Form;
 use serde::Deserialize;
 use std::collections::HashMap;
 
 pub struct SauceNaoImageSearcher {
     pub api_key: String,
+    pub config: cfg::SearcherConfig,
 }
 
 #[derive(Deserialize, Debug)]
@@ -37,56 +40,75 @@ impl ImageSearcher for SauceNaoImageSearcher {
         "saucenao"
     }
 
+    fn get_config(&self) -> &cfg::SearcherConfig {
+        &self.config
+    }
+
     async fn search(&self, url: &str) -> ImageSearchResult {
         let result: SauceNaoImageSearchResult = CLIENT
             .get("https://saucenao.com/search.php")
-            .query(&[
-                ("db", "999"),
-                ("numres", "3"),
-                ("api_key", self.api_key.as_ref()),
-                ("output_type", "2"),
-                ("url", url.as_ref()),
-            ])
+            .query(&self.build_query())
+            .query(&[("url", url)])
             .send()
             .await?
             .error_for_status()?
             .json()
             .await?;
-        match &result.results[..] {
-            [result, ..] => {
-                let similarity = result.header.similarity.parse::<f64>()?;
+        self.parse_result(result)
+    }
 
-                if similarity < 0.95
-                    || result.data.ext_urls.is_none()
-                    || result.data.ext_urls.as_ref().unwrap().is_empty()
-                {
-                    return Ok(None);
-                }
+    async fn search_bytes(&self, image: &[u8]) -> ImageSearchResult {
+        let result: SauceNaoImageSearchResult = CLIENT
+            .post("https://saucenao.com/search.php")
+            .query(&self.build_query())
+            .multipart(Form::new().part("file", image_part(image)))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        self.parse_result(result)
+    }
+}
 
-                let mut metadata: HashMap<String, String> = HashMap::new();
-                if let Some(author_name) = &result.data.author_name {
-                    metadata.insert("作者".to_string(), author_name.clone());
-                }
-                if let Some(title) = &result.data.title {
-                    metadata.insert("标题".to_string(), title.clone());
-                }
+impl SauceNaoImageSearcher {
+    fn build_query(&self) -> [(&str, String); 4] {
+        [
+            ("db", "999".to_string()),
+            ("numres", self.config.max_results.to_string()),
+            ("api_key", self.api_key.clone()),
+            ("output_type", "2".to_string()),
+        ]
+    }
 
-                let url = result
-                    .data
-                    .ext_urls
-                    .as_ref()
-                    .unwrap()
-                    .get(0)
-                    .unwrap()
-                    .clone();
+    fn parse_result(&self, result: SauceNaoImageSearchResult) -> ImageSearchResult {
+        let mut images = vec![];
+        for result in result.results {
+            // SauceNAO reports the similarity as a percentage, e.g. "93.21"
+            let similarity = result.header.similarity.parse::<f64>()? / 100.0;
 
-                return Ok(Some(SourceImage {
-                    url,
-                    searcher: self.get_name(),
-                    metadata,
-                }));
+            let url = match result.data.ext_urls.as_deref() {
+                Some([url, ..]) => url.clone(),
+                _ => continue,
+            };
+
+            let mut metadata: HashMap<String, String> = HashMap::new();
+            if let Some(author_name) = result.data.author_name {
+                metadata.insert("作者".to_string(), author_name);
+            }
+            if let Some(title) = result.data.title {
+                metadata.insert("标题".to_string(), title);
             }
-            _ => Ok(None),
+
+            images.push(SourceImage {
+                url,
+                searcher: self.get_name(),
+                similarity,
+                low_confidence: false,
+                metadata,
+                preview: None,
+            });
         }
+        Ok(images)
     }
 }
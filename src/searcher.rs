@@ -1,30 +1,63 @@
-use crate::cfg;
-use crate::database::*;
+use crate::aggregator::{self, AggregatedImage};
+use crate::booru;
+use crate::cache;
+use crate::cfg::{self, SearchReplyMode};
+use crate::client::CLIENT;
+use crate::ehentai;
+use crate::health::{GuardedSearcher, SearcherError};
 use crate::iqdb;
 use crate::message::*;
+use crate::messages::{self, MessageKey, StoredImage};
+use crate::pixiv;
 use crate::saucenao;
+use crate::sender;
+use crate::storage::STORAGE;
+use crate::tracemoe;
 use crate::{ascii2d, utils};
 use anyhow::Result;
 use async_trait::async_trait;
+use futures::stream::{FuturesUnordered, StreamExt};
 use lazy_static::lazy_static;
-use log::error;
+use log::{debug, error, warn};
 use regex::Regex;
+use reqwest::multipart::Part;
 use std::collections::HashMap;
+use std::time::Duration;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Default)]
 pub struct SourceImage {
     pub url: String,
     pub searcher: &'static str,
+    /// Normalized confidence score in the range 0.0 - 1.0. Engines that do not
+    /// report a score use a fixed value reflecting how reliable their hits are.
+    pub similarity: f64,
+    pub low_confidence: bool,
     pub metadata: HashMap<String, String>,
+    /// A video clip to be sent along with the result.
+    pub preview: Option<String>,
 }
 
-pub type ImageSearchResult = Result<Option<SourceImage>>;
+pub type ImageSearchResult = Result<Vec<SourceImage>>;
 
 #[async_trait]
 pub trait ImageSearcher {
     fn get_name(&self) -> &'static str;
 
+    fn get_config(&self) -> &cfg::SearcherConfig;
+
     async fn search(&self, url: &str) -> ImageSearchResult;
+
+    /// Searches by uploading the image, as remote engines often cannot reach QQ image urls.
+    async fn search_bytes(&self, image: &[u8]) -> ImageSearchResult;
+}
+
+/// Builds the multipart part of an image to be uploaded.
+pub fn image_part(image: &[u8]) -> Part {
+    let extension = image::guess_format(image)
+        .ok()
+        .and_then(|format| format.extensions_str().first())
+        .unwrap_or(&"jpg");
+    Part::bytes(image.to_vec()).file_name(format!("image.{}", extension))
 }
 
 lazy_static! {
@@ -32,24 +65,33 @@ lazy_static! {
     static ref REPLY_ID_REGEX: Regex = Regex::new(r"id=([^]]+)]").unwrap();
 }
 
-pub async fn on_group_message(message: OneBotGroupMessage) -> Option<BotResponseAction> {
+pub async fn on_group_message(message: OneBotGroupMessage) -> Vec<BotResponseAction> {
     let OneBotGroupMessage {
         ref message,
         message_id,
         group_id,
+        self_id,
         ..
     } = message;
 
     if message.contains("[CQ:image") {
         if let Some(caps) = IMAGE_URL_REGEX.captures(message) {
             if caps.len() < 2 {
-                return None;
+                return vec![];
             }
-            if let Err(err) =
-                DATABASE.insert(format!("image_url:{}", message_id).as_str(), &caps[1])
-            {
+            let image = StoredImage {
+                url: caps[1].to_string(),
+                md5: cache::extract_image_md5(message),
+                time: utils::unix_timestamp(),
+            };
+            let key = MessageKey {
+                self_id,
+                group_id,
+                message_id,
+            };
+            if let Err(err) = STORAGE.messages.insert(&key, &image) {
                 error!("failed to insert record into database: {}", err);
-                return None;
+                return vec![];
             }
         }
     }
@@ -57,102 +99,395 @@ pub async fn on_group_message(message: OneBotGroupMessage) -> Option<BotResponse
     if message.contains("[CQ:reply") && (message.contains("查出处") || message.contains("ccc")) {
         if let Some(caps) = REPLY_ID_REGEX.captures(message) {
             if caps.len() < 2 {
-                return None;
+                return vec![];
             }
 
-            if let Ok(ref reply_id) = caps[1].parse::<i32>() {
-                return match DATABASE.get(format!("image_url:{}", reply_id).as_str()) {
-                    Ok(None) => None,
+            if let Ok(reply_id) = caps[1].parse::<i32>() {
+                let key = MessageKey {
+                    self_id,
+                    group_id,
+                    message_id: reply_id,
+                };
+                return match messages::get(key) {
+                    Ok(None) => vec![],
                     Err(err) => {
                         error!("failed to get record from database: {}", err);
-                        None
+                        vec![]
                     }
-                    Ok(Some(image_url)) => {
-                        let image_url = String::from_utf8(image_url.to_vec()).unwrap();
-                        let message = match search_image(image_url.as_str()).await.as_slice() {
-                            images @ [_, ..] => {
-                                format!("[CQ:reply,id={}]{}", message_id, parse_result(images))
-                            }
-                            _ => format!("[CQ:reply,id={}]并没有找到出处", message_id),
-                        };
-                        Some(BotResponseAction::GroupMessage { group_id, message })
+                    Ok(Some(image)) => {
+                        reply_search(&image.url, image.md5, group_id, message_id).await
                     }
                 };
             }
         }
     }
 
-    None
+    vec![]
 }
 
 lazy_static! {
-    static ref SEARCHERS: Box<[Box<dyn ImageSearcher + Send + Sync>]> = Box::new([
-        Box::new(ascii2d::Ascii2dImageSearcher {}),
-        Box::new(saucenao::SauceNaoImageSearcher {
-            api_key: cfg::BOT_CONFIG.saucenao_api_key.clone()
-        }),
-        Box::new(iqdb::IqdbImageSearcher {})
-    ]);
+    pub static ref SEARCHERS: Box<[GuardedSearcher]> = {
+        iqdb::check_services(&cfg::BOT_CONFIG.iqdb_services);
+        let mut searchers: Vec<Box<dyn ImageSearcher + Send + Sync>> = vec![
+            Box::new(ascii2d::Ascii2dImageSearcher {
+                config: cfg::BOT_CONFIG.ascii2d.clone(),
+            }),
+            Box::new(saucenao::SauceNaoImageSearcher {
+                api_key: cfg::BOT_CONFIG.saucenao_api_key.clone(),
+                config: cfg::BOT_CONFIG.saucenao.clone(),
+            }),
+            Box::new(iqdb::IqdbImageSearcher {
+                config: cfg::BOT_CONFIG.iqdb.clone(),
+                is_3d: false,
+                services: cfg::BOT_CONFIG.iqdb_services.clone(),
+            }),
+            Box::new(tracemoe::TraceMoeImageSearcher {
+                config: cfg::BOT_CONFIG.tracemoe.clone(),
+                send_preview: cfg::BOT_CONFIG.tracemoe_send_preview,
+            }),
+            Box::new(ehentai::EHentaiImageSearcher {
+                config: cfg::BOT_CONFIG.ehentai.clone(),
+                exhentai_cookie: cfg::BOT_CONFIG.exhentai_cookie.clone(),
+            }),
+        ];
+        if let Some(config) = &cfg::BOT_CONFIG.iqdb_3d {
+            searchers.push(Box::new(iqdb::IqdbImageSearcher {
+                config: config.clone(),
+                is_3d: true,
+                services: cfg::BOT_CONFIG.iqdb_services.clone(),
+            }));
+        }
+        searchers
+            .into_iter()
+            .map(|searcher| {
+                GuardedSearcher::new(
+                    searcher,
+                    cfg::BOT_CONFIG.breaker_failure_threshold,
+                    Duration::from_secs(cfg::BOT_CONFIG.breaker_cooldown_secs),
+                )
+            })
+            .collect()
+    };
+}
+
+static RESULT_HEADER: &str = "🥵🥵🥵 色图出处 👇👇👇";
+static LATE_RESULT_HEADER: &str = "⏰ 补充出处 👇👇👇";
+
+async fn reply_search(
+    image_url: &str,
+    image_md5: Option<String>,
+    group_id: i64,
+    message_id: i32,
+) -> Vec<BotResponseAction> {
+    if let Some(images) = image_md5.as_deref().and_then(cache::get) {
+        return build_replies(&images, RESULT_HEADER, group_id, message_id);
+    }
+
+    let (images, sent_urls, complete) = search_and_enrich(image_url, group_id, message_id).await;
+    // results of an early exit miss the searchers which have not finished
+    if let (Some(md5), [_, ..], true) = (&image_md5, images.as_slice(), complete) {
+        cache::insert(md5, &images);
+    }
+    if sent_urls.is_empty() {
+        return build_replies(&images, RESULT_HEADER, group_id, message_id);
+    }
+
+    let late_images = images
+        .into_iter()
+        .filter(|image| !sent_urls.contains(&image.url))
+        .collect::<Vec<AggregatedImage>>();
+    if late_images.is_empty() {
+        return vec![];
+    }
+    build_replies(&late_images, LATE_RESULT_HEADER, group_id, message_id)
 }
 
-async fn search_image(url: &str) -> Vec<SourceImage> {
-    let tasks = SEARCHERS.iter().map(|searcher| searcher.search(url));
-    let results = futures::future::join_all(tasks).await;
-    results
+fn build_replies(
+    images: &[AggregatedImage],
+    header: &str,
+    group_id: i64,
+    message_id: i32,
+) -> Vec<BotResponseAction> {
+    let message = match images {
+        [_, ..] => format!(
+            "[CQ:reply,id={}]{}",
+            message_id,
+            parse_result(images, header)
+        ),
+        _ => format!("[CQ:reply,id={}]并没有找到出处", message_id),
+    };
+    // go-cqhttp does not allow videos to be mixed with other message segments
+    let previews = images
+        .iter()
+        .filter_map(|image| image.preview.as_ref())
+        .map(|preview| BotResponseAction::GroupMessage {
+            group_id,
+            message: format!("[CQ:video,file={}]", preview),
+        });
+    [BotResponseAction::GroupMessage { group_id, message }]
         .into_iter()
+        .chain(previews)
+        .collect()
+}
+
+/// Returns the enriched results, the urls of those already replied in progressive mode, and
+/// whether all searchers have finished.
+async fn search_and_enrich(
+    url: &str,
+    group_id: i64,
+    message_id: i32,
+) -> (Vec<AggregatedImage>, Vec<String>, bool) {
+    let mode = &cfg::BOT_CONFIG.search_reply_mode;
+    let is_confident = |image: &SourceImage| {
+        !image.low_confidence && image.similarity >= cfg::BOT_CONFIG.early_reply_similarity
+    };
+
+    // download the image once and upload it to every searcher
+    let image = match fetch_image(url).await {
+        Ok(image) => Some(image),
+        Err(err) => {
+            warn!("failed to download {}, searching by url: {:#?}", url, err);
+            None
+        }
+    };
+    let image = image.as_deref();
+    let mut tasks = SEARCHERS
+        .iter()
         .enumerate()
-        .filter_map(|(i, result)| match result {
-            Ok(Some(image)) => Some(image),
-            Ok(None) => {
+        .map(|(i, searcher)| async move { (i, search_with(searcher, url, image).await) })
+        .collect::<FuturesUnordered<_>>();
+    let mut images = vec![];
+    let mut sent_urls = vec![];
+    let mut early_reply = None;
+    let mut complete = true;
+    while let Some((i, result)) = tasks.next().await {
+        images.extend(handle_search_result(url, i, result));
+        if *mode == SearchReplyMode::All
+            || !sent_urls.is_empty()
+            || !images.iter().any(is_confident)
+        {
+            continue;
+        }
+
+        if *mode == SearchReplyMode::EarlyExit {
+            complete = tasks.is_empty();
+            break;
+        }
+        let mut early_images = aggregator::aggregate(images.clone());
+        sent_urls = early_images.iter().map(|image| image.url.clone()).collect();
+        // the remaining searchers keep running while the first results are enriched and sent
+        early_reply = Some(tokio::spawn(async move {
+            booru::enrich(&mut early_images).await;
+            pixiv::enrich(&mut early_images).await;
+            for action in build_replies(&early_images, RESULT_HEADER, group_id, message_id) {
+                sender::send(action).await;
+            }
+        }));
+    }
+
+    let mut images = aggregator::aggregate(images);
+    booru::enrich(&mut images).await;
+    pixiv::enrich(&mut images).await;
+    // make sure the late results are sent after the early ones
+    if let Some(early_reply) = early_reply {
+        if let Err(err) = early_reply.await {
+            error!("failed to send early search results: {:#?}", err);
+        }
+    }
+    (images, sent_urls, complete)
+}
+
+pub async fn fetch_image(url: &str) -> Result<Vec<u8>> {
+    let image = CLIENT
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+    Ok(image.to_vec())
+}
+
+async fn search_with(
+    searcher: &GuardedSearcher,
+    url: &str,
+    image: Option<&[u8]>,
+) -> ImageSearchResult {
+    let image = match image {
+        Some(image) => image,
+        None => return searcher.search(url).await,
+    };
+    let max_bytes = searcher.get_config().max_upload_bytes;
+    if image.len() as u64 <= max_bytes {
+        return searcher.search_bytes(image).await;
+    }
+
+    let original = image.to_vec();
+    match tokio::task::spawn_blocking(move || utils::shrink_image(&original, max_bytes)).await {
+        Ok(Ok(image)) => searcher.search_bytes(&image).await,
+        Ok(Err(err)) => {
+            warn!(
+                "failed to shrink {} for {}, searching by url: {:#?}",
+                url,
+                searcher.get_name(),
+                err
+            );
+            searcher.search(url).await
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn handle_search_result(url: &str, i: usize, result: ImageSearchResult) -> Vec<SourceImage> {
+    match result {
+        Ok(images) => {
+            let images = rank_images(images, SEARCHERS[i].get_config());
+            if images.is_empty() {
                 error!(
                     "source image not found for {} using {}",
                     url,
                     SEARCHERS[i].get_name()
                 );
-                None
-            }
-            Err(err) => {
-                error!(
-                    "failed to search image {} using {}: {:#?}",
-                    url,
-                    SEARCHERS[i].get_name(),
-                    err
-                );
-                None
             }
+            images
+        }
+        Err(err) if matches!(err.downcast_ref(), Some(SearcherError::CircuitOpen(_))) => {
+            debug!("{}", err);
+            vec![]
+        }
+        Err(err) => {
+            error!(
+                "failed to search image {} using {}: {:#?}",
+                url,
+                SEARCHERS[i].get_name(),
+                err
+            );
+            vec![]
+        }
+    }
+}
+
+fn rank_images(mut images: Vec<SourceImage>, config: &cfg::SearcherConfig) -> Vec<SourceImage> {
+    images.sort_by(|a, b| b.similarity.total_cmp(&a.similarity));
+    images
+        .into_iter()
+        .map(|image| SourceImage {
+            low_confidence: image.similarity < config.min_similarity,
+            ..image
         })
+        .filter(|image| !(config.drop_low_confidence && image.low_confidence))
+        .take(config.max_results)
         .collect()
 }
 
-fn parse_result(images: &[SourceImage]) -> String {
+fn parse_result(images: &[AggregatedImage], header: &str) -> String {
     images
         .iter()
-        .fold(
-            "🥵🥵🥵 色图出处 👇👇👇\n\n".to_string(),
-            |mut result, image| {
-                let url = {
-                    if let Some(pixiv_id) = utils::extract_pixiv_artwork_id(image.url.as_str()) {
-                        format!(
-                            "{}\n国内加速: https://pixiv.re/{}.png",
-                            image.url.as_str(),
-                            pixiv_id
-                        )
-                    } else {
-                        image.url.clone()
-                    }
-                };
-                result.push_str(
+        .fold(format!("{}\n\n", header), |mut result, image| {
+            let url = {
+                if let Some(pixiv_id) = utils::extract_pixiv_artwork_id(image.url.as_str()) {
                     format!(
-                        "⚠️ {}\n{}\n{}\n\n",
-                        image.searcher,
-                        utils::serialize_hashmap(&image.metadata),
-                        url
+                        "{}\n国内加速: https://pixiv.re/{}.png",
+                        image.url.as_str(),
+                        pixiv_id
                     )
-                    .as_str(),
-                );
-                result
-            },
-        )
+                } else {
+                    image.url.clone()
+                }
+            };
+            let label = if image.low_confidence {
+                "（低可信度）"
+            } else {
+                ""
+            };
+            result.push_str(
+                format!(
+                    "⚠️ {} 相似度 {:.1}%{}\n{}\n{}\n\n",
+                    image.searchers.join(", "),
+                    image.similarity * 100.0,
+                    label,
+                    utils::serialize_hashmap(&image.metadata),
+                    url
+                )
+                .as_str(),
+            );
+            result
+        })
         .trim_end()
         .to_string()
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::cfg::SearcherConfig;
+    use crate::searcher::{rank_images, SourceImage};
+
+    #[test]
+    fn rank_images_test_1() {
+        let config = SearcherConfig {
+            min_similarity: 0.8,
+            drop_low_confidence: false,
+            max_results: 2,
+            ..SearcherConfig::saucenao()
+        };
+        let images = rank_images(
+            vec![
+                SourceImage {
+                    url: "a".to_string(),
+                    similarity: 0.5,
+                    ..Default::default()
+                },
+                SourceImage {
+                    url: "b".to_string(),
+                    similarity: 0.9,
+                    ..Default::default()
+                },
+                SourceImage {
+                    url: "c".to_string(),
+                    similarity: 0.7,
+                    ..Default::default()
+                },
+            ],
+            &config,
+        );
+        assert_eq!(
+            images
+                .iter()
+                .map(|image| (image.url.as_str(), image.low_confidence))
+                .collect::<Vec<_>>(),
+            vec![("b", false), ("c", true)]
+        );
+    }
+
+    #[test]
+    fn rank_images_test_2() {
+        let config = SearcherConfig {
+            min_similarity: 0.8,
+            drop_low_confidence: true,
+            max_results: 3,
+            ..SearcherConfig::saucenao()
+        };
+        let images = rank_images(
+            vec![
+                SourceImage {
+                    url: "a".to_string(),
+                    similarity: 0.5,
+                    ..Default::default()
+                },
+                SourceImage {
+                    url: "b".to_string(),
+                    similarity: 0.9,
+                    ..Default::default()
+                },
+            ],
+            &config,
+        );
+        assert_eq!(
+            images
+                .iter()
+                .map(|image| image.url.as_str())
+                .collect::<Vec<_>>(),
+            vec!["b"]
+        );
+    }
+}
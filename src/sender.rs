@@ -0,0 +1,33 @@
+use crate::message::BotResponseAction;
+use lazy_static::lazy_static;
+use log::error;
+use std::sync::Mutex;
+use tokio::sync::mpsc;
+
+lazy_static! {
+    static ref SENDER: Mutex<Option<mpsc::Sender<BotResponseAction>>> = Mutex::new(None);
+}
+
+/// Registers the channel of the websocket writer, must be called before `send`.
+pub fn init(sender: mpsc::Sender<BotResponseAction>) {
+    let mut current = SENDER.lock().unwrap();
+    if current.is_some() {
+        error!("sender has already been initialized");
+        return;
+    }
+    *current = Some(sender);
+}
+
+/// Sends an action outside of the reply returned by a message handler.
+pub async fn send(action: BotResponseAction) {
+    // cloned so that the lock is not held across the await
+    let sender = SENDER.lock().unwrap().clone();
+    match sender {
+        Some(sender) => {
+            if let Err(err) = sender.send(action).await {
+                error!("failed to send message: {:#?}", err);
+            }
+        }
+        None => error!("sender is not initialized, dropping {:?}", action),
+    }
+}
@@ -0,0 +1,274 @@
+use crate::cache::CachedSearchResult;
+use crate::database::*;
+use crate::download_queue::{self, DownloadJob};
+use crate::messages::{self, MessageKey, StoredImage};
+use crate::utils;
+use anyhow::Result;
+use lazy_static::lazy_static;
+use log::info;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::marker::PhantomData;
+
+lazy_static! {
+    pub static ref STORAGE: Storage = Storage::open(&DATABASE).expect("failed to open storage");
+}
+
+/// Version of the layout of the stored data, bumped along with a new migration.
+pub const SCHEMA_VERSION: u32 = 2;
+
+type Migration = fn(&Storage) -> Result<()>;
+
+/// Migrations to each version from the previous one.
+static MIGRATIONS: [(u32, Migration); 2] = [
+    (1, migrate_legacy_message_keys),
+    (2, migrate_legacy_download_records),
+];
+
+fn migrate_legacy_message_keys(storage: &Storage) -> Result<()> {
+    let migrated =
+        messages::migrate_legacy_keys(&storage.db, &storage.messages, utils::unix_timestamp())?;
+    info!("migrated {} legacy message keys", migrated);
+    Ok(())
+}
+
+fn migrate_legacy_download_records(storage: &Storage) -> Result<()> {
+    let migrated = download_queue::migrate_legacy_records(&storage.downloads.tree)?;
+    info!("migrated {} legacy download records", migrated);
+    Ok(())
+}
+
+/// Keys of a repository, encoded so that the order of the bytes follows the order of the keys.
+pub trait StorageKey {
+    fn to_key(&self) -> Vec<u8>;
+}
+
+impl StorageKey for str {
+    fn to_key(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+}
+
+impl StorageKey for u64 {
+    fn to_key(&self) -> Vec<u8> {
+        self.to_be_bytes().to_vec()
+    }
+}
+
+impl StorageKey for i64 {
+    fn to_key(&self) -> Vec<u8> {
+        self.to_be_bytes().to_vec()
+    }
+}
+
+impl StorageKey for MessageKey {
+    fn to_key(&self) -> Vec<u8> {
+        self.to_bytes().to_vec()
+    }
+}
+
+/// A sled tree whose values are JSON-encoded `V`s.
+pub struct Repository<K: ?Sized, V> {
+    tree: sled::Tree,
+    marker: PhantomData<fn(&K) -> V>,
+}
+
+impl<K: StorageKey + ?Sized, V: Serialize + DeserializeOwned> Repository<K, V> {
+    pub fn open(db: &sled::Db, name: &str) -> Result<Self> {
+        Ok(Repository {
+            tree: db.open_tree(name)?,
+            marker: PhantomData,
+        })
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        match self.tree.get(key.to_key())? {
+            Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn insert(&self, key: &K, value: &V) -> Result<()> {
+        self.tree.insert(key.to_key(), serde_json::to_vec(value)?)?;
+        Ok(())
+    }
+
+    /// Inserts the value unless the key exists, atomically. Returns whether it is inserted.
+    pub fn insert_new(&self, key: &K, value: &V) -> Result<bool> {
+        Ok(self
+            .tree
+            .compare_and_swap(
+                key.to_key(),
+                None as Option<&[u8]>,
+                Some(serde_json::to_vec(value)?),
+            )?
+            .is_ok())
+    }
+
+    /// Returns whether the key existed.
+    pub fn remove(&self, key: &K) -> Result<bool> {
+        Ok(self.tree.remove(key.to_key())?.is_some())
+    }
+
+    /// Returns the values in the order of their keys.
+    pub fn values(&self) -> Result<Vec<V>> {
+        self.tree
+            .iter()
+            .values()
+            .map(|value| Ok(serde_json::from_slice(&value?)?))
+            .collect()
+    }
+
+    pub fn count(&self) -> usize {
+        self.tree.len()
+    }
+
+    pub fn clear(&self) -> Result<()> {
+        Ok(self.tree.clear()?)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, PartialEq)]
+pub struct Permissions {
+    pub admin: bool,
+}
+
+/// The repositories of each domain, and the settings including the schema version.
+///
+/// The archive, the repost indexes and the local hashes are left out, as they are kept in
+/// layouts of their own, e.g. the chunked keys of the repost indexes.
+pub struct Storage {
+    db: sled::Db,
+    pub messages: Repository<MessageKey, StoredImage>,
+    pub search_cache: Repository<str, CachedSearchResult>,
+    pub downloads: Repository<u64, DownloadJob>,
+    /// Reserved for granted permissions, admins are only configured by `admin_user_id` so far.
+    #[allow(dead_code)]
+    pub permissions: Repository<i64, Permissions>,
+    pub settings: Repository<str, serde_json::Value>,
+    /// Paths of the downloaded files relative to `download_path` by their urls.
+    pub file_names: Repository<str, String>,
+    /// Urls of the downloaded files by their relative paths, which reserves the paths.
+    pub file_paths: Repository<str, String>,
+}
+
+impl Storage {
+    pub fn open(db: &sled::Db) -> Result<Self> {
+        Ok(Storage {
+            db: db.clone(),
+            messages: Repository::open(db, "messages")?,
+            search_cache: Repository::open(db, "search_cache")?,
+            downloads: Repository::open(db, "downloads")?,
+            permissions: Repository::open(db, "permissions")?,
+            settings: Repository::open(db, "settings")?,
+            file_names: Repository::open(db, "file_names")?,
+            file_paths: Repository::open(db, "file_paths")?,
+        })
+    }
+
+    pub fn generate_id(&self) -> Result<u64> {
+        Ok(self.db.generate_id()?)
+    }
+
+    /// Databases created before the schema was versioned are at version 0.
+    pub fn schema_version(&self) -> Result<u32> {
+        match self.settings.get("schema_version")? {
+            Some(version) => Ok(serde_json::from_value(version)?),
+            None => Ok(0),
+        }
+    }
+
+    /// Runs the migrations newer than the stored schema version, returns the new version.
+    pub fn migrate(&self) -> Result<u32> {
+        let mut version = self.schema_version()?;
+        for (target, migration) in MIGRATIONS.iter() {
+            if *target <= version {
+                continue;
+            }
+            info!("migrating storage from version {} to {}", version, target);
+            migration(self)?;
+            self.settings
+                .insert("schema_version", &serde_json::json!(target))?;
+            version = *target;
+        }
+        self.db.flush()?;
+        Ok(version)
+    }
+}
+
+/// Aborts the startup if the storage can not be migrated, or is written by a newer version.
+pub fn migrate() {
+    match STORAGE.migrate() {
+        Ok(SCHEMA_VERSION) => {}
+        Ok(version) => panic!(
+            "storage is at version {}, expected {}",
+            version, SCHEMA_VERSION
+        ),
+        Err(err) => panic!("failed to migrate storage: {:#?}", err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::messages::MessageKey;
+    use crate::storage::{Permissions, Repository, Storage, SCHEMA_VERSION};
+
+    #[test]
+    fn repository_test() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let repository: Repository<i64, Permissions> = Repository::open(&db, "test").unwrap();
+        assert_eq!(repository.get(&1).unwrap(), None);
+        repository.insert(&1, &Permissions { admin: true }).unwrap();
+        repository
+            .insert(&-1, &Permissions { admin: false })
+            .unwrap();
+        assert_eq!(
+            repository.get(&1).unwrap(),
+            Some(Permissions { admin: true })
+        );
+        assert_eq!(repository.count(), 2);
+        assert!(!repository
+            .insert_new(&1, &Permissions { admin: false })
+            .unwrap());
+        assert!(repository
+            .insert_new(&2, &Permissions { admin: false })
+            .unwrap());
+        assert_eq!(
+            repository.get(&1).unwrap(),
+            Some(Permissions { admin: true })
+        );
+        assert!(repository.remove(&1).unwrap());
+        assert!(!repository.remove(&1).unwrap());
+        repository.clear().unwrap();
+        assert_eq!(repository.count(), 0);
+
+        db.open_tree("test")
+            .unwrap()
+            .insert(1i64.to_be_bytes(), "malformed")
+            .unwrap();
+        assert!(repository.get(&1).is_err());
+    }
+
+    #[test]
+    fn migrate_test() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        db.insert("image_url:1", "https://example.com/1").unwrap();
+        let storage = Storage::open(&db).unwrap();
+        assert_eq!(storage.schema_version().unwrap(), 0);
+
+        assert_eq!(storage.migrate().unwrap(), SCHEMA_VERSION);
+        assert_eq!(storage.schema_version().unwrap(), SCHEMA_VERSION);
+        let migrated = storage
+            .messages
+            .get(&MessageKey::legacy(1))
+            .unwrap()
+            .unwrap();
+        assert_eq!(migrated.url, "https://example.com/1");
+        assert!(migrated.time > 0);
+
+        // migrations are not run again
+        db.insert("image_url:2", "https://example.com/2").unwrap();
+        assert_eq!(storage.migrate().unwrap(), SCHEMA_VERSION);
+        assert_eq!(storage.messages.get(&MessageKey::legacy(2)).unwrap(), None);
+    }
+}
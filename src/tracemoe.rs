@@ -0,0 +1,196 @@
+use crate::cfg;
+use crate::client::CLIENT;
+use crate::searcher::*;
+use anyhow::bail;
+use async_trait::async_trait;
+use reqwest::multipart::Form;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+pub struct TraceMoeImageSearcher {
+    pub config: cfg::SearcherConfig,
+    pub send_preview: bool,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TraceMoeSearchResult {
+    pub error: String,
+    pub result: Vec<TraceMoeSearchResultItem>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TraceMoeSearchResultItem {
+    pub anilist: TraceMoeAnilistInfo,
+    pub episode: Option<serde_json::Value>,
+    pub from: f64,
+    pub to: f64,
+    pub similarity: f64,
+    pub video: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TraceMoeAnilistInfo {
+    pub id: i64,
+    pub title: TraceMoeAnilistTitle,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TraceMoeAnilistTitle {
+    pub native: Option<String>,
+    pub romaji: Option<String>,
+    pub english: Option<String>,
+}
+
+#[async_trait]
+impl ImageSearcher for TraceMoeImageSearcher {
+    fn get_name(&self) -> &'static str {
+        "trace.moe"
+    }
+
+    fn get_config(&self) -> &cfg::SearcherConfig {
+        &self.config
+    }
+
+    async fn search(&self, url: &str) -> ImageSearchResult {
+        let json = CLIENT
+            .get("https://api.trace.moe/search?anilistInfo")
+            .query(&[("url", url)])
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        self.parse_result(&json)
+    }
+
+    async fn search_bytes(&self, image: &[u8]) -> ImageSearchResult {
+        let json = CLIENT
+            .post("https://api.trace.moe/search?anilistInfo")
+            .multipart(Form::new().part("image", image_part(image)))
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        self.parse_result(&json)
+    }
+}
+
+impl TraceMoeImageSearcher {
+    fn parse_result(&self, json: &str) -> ImageSearchResult {
+        let result: TraceMoeSearchResult = serde_json::from_str(json)?;
+        if !result.error.is_empty() {
+            bail!("trace.moe returns an error: {}", result.error);
+        }
+
+        Ok(result
+            .result
+            .into_iter()
+            .map(|item| {
+                let mut metadata: HashMap<String, String> = HashMap::new();
+                let title = item.anilist.title;
+                if let Some(title) = title.native.or(title.romaji).or(title.english) {
+                    metadata.insert("番剧".to_string(), title);
+                }
+                match item.episode {
+                    Some(serde_json::Value::Number(episode)) => {
+                        metadata.insert("集数".to_string(), episode.to_string());
+                    }
+                    Some(serde_json::Value::String(episode)) => {
+                        metadata.insert("集数".to_string(), episode);
+                    }
+                    _ => {}
+                }
+                metadata.insert(
+                    "时间".to_string(),
+                    format!(
+                        "{} - {}",
+                        format_timestamp(item.from),
+                        format_timestamp(item.to)
+                    ),
+                );
+
+                SourceImage {
+                    url: format!("https://anilist.co/anime/{}", item.anilist.id),
+                    searcher: self.get_name(),
+                    similarity: item.similarity,
+                    low_confidence: false,
+                    metadata,
+                    preview: self.send_preview.then_some(item.video),
+                }
+            })
+            .collect())
+    }
+}
+
+fn format_timestamp(seconds: f64) -> String {
+    let seconds = seconds as u64;
+    if seconds >= 3600 {
+        format!(
+            "{}:{:02}:{:02}",
+            seconds / 3600,
+            seconds % 3600 / 60,
+            seconds % 60
+        )
+    } else {
+        format!("{:02}:{:02}", seconds / 60, seconds % 60)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cfg::SearcherConfig;
+    use crate::tracemoe::TraceMoeImageSearcher;
+
+    #[test]
+    fn parse_tracemoe_response() {
+        let searcher = TraceMoeImageSearcher {
+            config: SearcherConfig::tracemoe(),
+            send_preview: true,
+        };
+        let images = searcher
+            .parse_result(
+                r###"{"frameCount":745506,"error":"","result":[{"anilist":{"id":99939,"idMal":34658,"title":{"native":"ネコぱらOVA","romaji":"Nekopara OVA","english":null},"synonyms":["Nekopara OAD"],"isAdult":false},"filename":"Nekopara - OVA (BD 1280x720 x264 AAC).mp4","episode":1,"from":97.75,"to":98.92,"similarity":0.9440424588727485,"video":"https://media.trace.moe/video/99939/Nekopara%20-%20OVA%20(BD%201280x720%20x264%20AAC).mp4?t=98.335&now=1653892514&token=xxxxxxxxxxxxxx","image":"https://media.trace.moe/image/99939/Nekopara%20-%20OVA%20(BD%201280x720%20x264%20AAC).mp4.jpg?t=98.335&now=1653892514&token=xxxxxxxxxxxxxx"},{"anilist":{"id":21355,"idMal":31240,"title":{"native":"Re:ゼロから始める異世界生活","romaji":"Re:Zero kara Hajimeru Isekai Seikatsu","english":"Re:ZERO -Starting Life in Another World-"},"synonyms":[],"isAdult":false},"filename":"[Ohys-Raws] Re Zero kara Hajimeru Isekai Seikatsu - 18 (AT-X 1280x720 x264 AAC).mp4","episode":"18","from":3725.5,"to":3726.1,"similarity":0.7503918651468093,"video":"https://media.trace.moe/video/21355/foo.mp4","image":"https://media.trace.moe/image/21355/foo.jpg"}]}"###,
+            )
+            .unwrap();
+        assert_eq!(images.len(), 2);
+        assert_eq!(images[0].url, "https://anilist.co/anime/99939");
+        assert_eq!(images[0].metadata["番剧"], "ネコぱらOVA");
+        assert_eq!(images[0].metadata["集数"], "1");
+        assert_eq!(images[0].metadata["时间"], "01:37 - 01:38");
+        assert!(images[0]
+            .preview
+            .as_ref()
+            .unwrap()
+            .starts_with("https://media.trace.moe/video/99939/"));
+        assert_eq!(images[1].metadata["集数"], "18");
+        assert_eq!(images[1].metadata["时间"], "1:02:05 - 1:02:06");
+    }
+
+    #[test]
+    fn parse_tracemoe_response_without_preview() {
+        let searcher = TraceMoeImageSearcher {
+            config: SearcherConfig::tracemoe(),
+            send_preview: false,
+        };
+        let images = searcher
+            .parse_result(
+                r###"{"frameCount":0,"error":"","result":[{"anilist":{"id":99939,"title":{"native":null,"romaji":"Nekopara OVA","english":null}},"episode":null,"from":1,"to":2,"similarity":0.9,"video":"https://media.trace.moe/video/99939/foo.mp4"}]}"###,
+            )
+            .unwrap();
+        assert_eq!(images[0].metadata["番剧"], "Nekopara OVA");
+        assert!(!images[0].metadata.contains_key("集数"));
+        assert_eq!(images[0].preview, None);
+    }
+
+    #[test]
+    fn parse_tracemoe_error() {
+        let searcher = TraceMoeImageSearcher {
+            config: SearcherConfig::tracemoe(),
+            send_preview: false,
+        };
+        assert!(searcher
+            .parse_result(r###"{"frameCount":0,"error":"Invalid image url","result":[]}"###)
+            .is_err());
+    }
+}
@@ -1,14 +1,18 @@
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Result};
+use chrono::{Local, TimeZone};
 use futures_util::StreamExt;
 use lazy_static::lazy_static;
 use log::{info, warn};
 use regex::Regex;
-use reqwest::Response;
+use reqwest::header::{CONTENT_RANGE, ETAG, IF_RANGE, LAST_MODIFIED, RANGE};
+use reqwest::{RequestBuilder, Response, StatusCode};
 use std::collections::HashMap;
+use std::io::Cursor;
 use std::str::FromStr;
-use tokio::fs::File;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tokio::fs::{File, OpenOptions};
 use tokio::io::AsyncWriteExt;
-use url::Url;
 
 pub static DEFAULT_HEADER: &str = "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/102.0.0.0 Safari/537.36";
 
@@ -25,12 +29,42 @@ pub fn extract_filename_from_url(url: &url::Url) -> Option<(&str, Option<&str>)>
         )
 }
 
-pub async fn download_file_if_not_exists(response: Response, path: &str) -> Result<u64> {
-    let size = response.content_length().unwrap_or(0);
+/// Attempts to download a file, each continuing from where the previous one is interrupted.
+const MAX_DOWNLOAD_ATTEMPTS: u32 = 5;
 
+/// A download larger than the limit, with its size and the limit.
+#[derive(Debug, Error, PartialEq)]
+#[error("file size {0} exceeds the limit {1}")]
+pub struct SizeLimitExceeded(pub u64, pub u64);
+
+/// Called with the bytes received and the total size while downloading.
+pub type OnProgress = dyn Fn(u64, Option<u64>) + Send + Sync;
+
+/// Sends a copy of the request, so that the request can be sent again to resume a download.
+pub async fn send_cloned(request: &RequestBuilder) -> Result<Response> {
+    let request = request
+        .try_clone()
+        .ok_or_else(|| anyhow!("the request cannot be cloned"))?;
+    Ok(request.send().await?.error_for_status()?)
+}
+
+/// Downloads the body of `response` into `{path}.part`, and renames it to `path` once its size
+/// is verified and it is synced to the disk. Interrupted downloads are continued by sending
+/// `request` with a Range header, or restarted if the server does not support ranges.
+/// The ETag or Last-Modified of the file is kept in `{path}.part.validator` and sent in
+/// If-Range, so that a file changed on the server is downloaded again instead of resumed.
+/// Downloads larger than `max_bytes` are stopped and removed.
+pub async fn download_file_if_not_exists(
+    response: Response,
+    request: RequestBuilder,
+    path: &str,
+    on_progress: Option<&OnProgress>,
+    max_bytes: Option<u64>,
+) -> Result<u64> {
+    let size = response.content_length();
     if let Ok(metadata) = tokio::fs::metadata(&path).await {
         let len = metadata.len();
-        if len == size {
+        if Some(len) == size {
             info!(
                 "file already exists at {}, size: {}, skip downloading",
                 path, len
@@ -38,26 +72,280 @@ pub async fn download_file_if_not_exists(response: Response, path: &str) -> Resu
             return Ok(len);
         }
         warn!(
-            "file {} exists, but size unmatched, expected {} actual {}",
+            "file {} exists, but size unmatched, expected {:?} actual {}",
             path, size, len
         )
     }
 
-    info!(
-        "downloading file from {} to {}, size: {}",
-        response.url().as_str(),
-        path,
-        size
+    let part_path = format!("{}.part", path);
+    let validator_path = format!("{}.validator", part_path);
+    let mut validator = validator_of(&response);
+    let mut written = tokio::fs::metadata(&part_path)
+        .await
+        .map(|metadata| metadata.len())
+        .unwrap_or(0);
+    // resuming without a validator on either side trusts the server, as before validators
+    if written > 0 && tokio::fs::read_to_string(&validator_path).await.ok() != validator {
+        warn!(
+            "{} has changed since it was partially downloaded, restart downloading",
+            path
+        );
+        written = 0;
+    }
+    let mut first_response = Some(response);
+    let mut last_error: Option<anyhow::Error> = None;
+    for attempt in 1..=MAX_DOWNLOAD_ATTEMPTS {
+        if attempt > 1 {
+            tokio::time::sleep(Duration::from_millis(500 * attempt as u64)).await;
+        }
+        let response = match first_response.take() {
+            Some(response) if written == 0 => response,
+            _ => {
+                let mut request = request
+                    .try_clone()
+                    .ok_or_else(|| anyhow!("the request cannot be cloned"))?;
+                if written > 0 {
+                    request = request.header(RANGE, format!("bytes={}-", written));
+                    if let Some(validator) = &validator {
+                        request = request.header(IF_RANGE, validator);
+                    }
+                }
+                match request.send().await {
+                    Ok(response) => response,
+                    Err(err) => {
+                        warn!("failed to reconnect to download {}: {}", path, err);
+                        last_error = Some(err.into());
+                        continue;
+                    }
+                }
+            }
+        };
+
+        let (mut file, total, response) = match response.status() {
+            StatusCode::PARTIAL_CONTENT => {
+                let (start, total) = parse_content_range(&response);
+                if start != Some(written) {
+                    warn!(
+                        "unexpected range {:?} of {}, expected {}",
+                        start, path, written
+                    );
+                    tokio::fs::remove_file(&part_path).await?;
+                    written = 0;
+                    continue;
+                }
+                let file = OpenOptions::new().append(true).open(&part_path).await?;
+                (file, total, response)
+            }
+            StatusCode::RANGE_NOT_SATISFIABLE if written > 0 => {
+                // the previous attempt may have received the whole file
+                if parse_content_range(&response).1 == Some(written) {
+                    let file = File::open(&part_path).await?;
+                    return finish_download(file, &part_path, path, written).await;
+                }
+                tokio::fs::remove_file(&part_path).await?;
+                written = 0;
+                continue;
+            }
+            _ => {
+                let response = match response.error_for_status() {
+                    Ok(response) => response,
+                    Err(err) => {
+                        warn!("failed to download {}: {}", path, err);
+                        last_error = Some(err.into());
+                        continue;
+                    }
+                };
+                if written > 0 {
+                    warn!(
+                        "{} does not support ranges or has changed, restart downloading",
+                        path
+                    );
+                }
+                written = 0;
+                let total = response.content_length();
+                validator = validator_of(&response);
+                match &validator {
+                    Some(validator) => tokio::fs::write(&validator_path, validator).await?,
+                    None => remove_if_exists(&validator_path).await?,
+                }
+                (File::create(&part_path).await?, total, response)
+            }
+        };
+        info!(
+            "downloading file from {} to {}, size: {:?}, from: {}",
+            request_url(&request),
+            path,
+            total,
+            written
+        );
+
+        if let (Some(total), Some(max)) = (total, max_bytes) {
+            if total > max {
+                drop(file);
+                tokio::fs::remove_file(&part_path).await?;
+                remove_if_exists(&validator_path).await?;
+                return Err(SizeLimitExceeded(total, max).into());
+            }
+        }
+
+        let mut stream = response.bytes_stream();
+        let mut error = None;
+        while let Some(item) = stream.next().await {
+            match item {
+                Ok(chunk) => {
+                    file.write_all(&chunk).await?;
+                    written += chunk.len() as u64;
+                    if let Some(max) = max_bytes.filter(|max| written > *max) {
+                        drop(file);
+                        tokio::fs::remove_file(&part_path).await?;
+                        remove_if_exists(&validator_path).await?;
+                        return Err(SizeLimitExceeded(written, max).into());
+                    }
+                    if let Some(on_progress) = on_progress {
+                        on_progress(written, total);
+                    }
+                }
+                Err(err) => {
+                    error = Some(err);
+                    break;
+                }
+            }
+        }
+        file.flush().await?;
+
+        match (error, total) {
+            (None, Some(total)) if written == total => {
+                return finish_download(file, &part_path, path, written).await;
+            }
+            (None, None) => return finish_download(file, &part_path, path, written).await,
+            (None, Some(total)) if written > total => {
+                warn!("{} exceeds the expected size {}", part_path, total);
+                tokio::fs::remove_file(&part_path).await?;
+                written = 0;
+            }
+            (None, Some(total)) => warn!(
+                "download of {} is interrupted at {}/{}",
+                path, written, total
+            ),
+            (Some(err), _) => {
+                warn!(
+                    "download of {} is interrupted at {}: {}",
+                    path, written, err
+                );
+                last_error = Some(err.into());
+            }
+        }
+    }
+    let message = format!(
+        "failed to download {} after {} attempts",
+        path, MAX_DOWNLOAD_ATTEMPTS
     );
-    let mut file = File::create(&path).await?;
-    let mut stream = response.bytes_stream();
-    while let Some(item) = stream.next().await {
-        file.write_all(&item?).await?;
+    Err(match last_error {
+        Some(err) => err.context(message),
+        None => anyhow!(message),
+    })
+}
+
+/// Returns the strong ETag, or the Last-Modified of the response, which can be sent in
+/// If-Range. Weak ETags are not allowed there.
+fn validator_of(response: &Response) -> Option<String> {
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.to_string())
+    };
+    header(ETAG)
+        .filter(|etag| !etag.starts_with("W/"))
+        .or_else(|| header(LAST_MODIFIED))
+}
+
+async fn remove_if_exists(path: &str) -> Result<()> {
+    match tokio::fs::remove_file(path).await {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
     }
+}
 
+async fn finish_download(file: File, part_path: &str, path: &str, size: u64) -> Result<u64> {
+    file.sync_all().await?;
+    drop(file);
+    tokio::fs::rename(part_path, path).await?;
+    remove_if_exists(&format!("{}.validator", part_path)).await?;
     Ok(size)
 }
 
+fn request_url(request: &RequestBuilder) -> String {
+    request
+        .try_clone()
+        .and_then(|request| request.build().ok())
+        .map(|request| request.url().to_string())
+        .unwrap_or_default()
+}
+
+/// Returns the start and the total size in `Content-Range: bytes <start>-<end>/<total>`.
+fn parse_content_range(response: &Response) -> (Option<u64>, Option<u64>) {
+    let value = match response
+        .headers()
+        .get(CONTENT_RANGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("bytes "))
+    {
+        Some(value) => value,
+        None => return (None, None),
+    };
+    match value.split_once('/') {
+        Some((range, total)) => (
+            range
+                .split_once('-')
+                .and_then(|(start, _)| start.parse().ok()),
+            total.parse().ok(),
+        ),
+        None => (None, None),
+    }
+}
+
+/// Downscales and re-encodes an image as JPEG until it fits in `max_bytes`.
+pub fn shrink_image(image: &[u8], max_bytes: u64) -> Result<Vec<u8>> {
+    let original = image::load_from_memory(image)?;
+    let mut scale = (max_bytes as f64 / image.len() as f64).sqrt().min(1.0);
+    loop {
+        let width = (original.width() as f64 * scale) as u32;
+        let height = (original.height() as f64 * scale) as u32;
+        if width < 16 || height < 16 {
+            bail!("failed to shrink the image to {} bytes", max_bytes);
+        }
+
+        let resized = image::DynamicImage::ImageRgb8(
+            original
+                .resize(width, height, image::imageops::FilterType::Triangle)
+                .to_rgb8(),
+        );
+        let mut output = Cursor::new(vec![]);
+        resized.write_to(&mut output, image::ImageOutputFormat::Jpeg(85))?;
+        if output.get_ref().len() as u64 <= max_bytes {
+            return Ok(output.into_inner());
+        }
+        scale *= 0.75;
+    }
+}
+
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// Formats a unix timestamp in the local timezone, e.g. `2022-10-01 12:00`.
+pub fn format_timestamp(timestamp: u64) -> String {
+    match Local.timestamp_opt(timestamp as i64, 0).single() {
+        Some(time) => time.format("%Y-%m-%d %H:%M").to_string(),
+        None => timestamp.to_string(),
+    }
+}
+
 pub fn serialize_hashmap(map: &HashMap<String, String>) -> String {
     let mut items: Vec<(&String, &String)> = map.iter().collect();
     items.sort();
@@ -94,24 +382,177 @@ pub fn extract_pixiv_artwork_id(url: &str) -> Option<String> {
     None
 }
 
-pub fn get_file_name(url: &Url) -> anyhow::Result<String> {
-    let result = url.path().split('/').into_iter().rev().next().map(|name| {
-        if name.contains('.') {
-            name.to_string()
-        } else {
-            format!("{}.mp4", name)
-        }
-    });
-    match result {
-        Some(name) => Ok(name),
-        None => bail!("failed to infer a file name from {}", url.as_str()),
-    }
-}
-
 #[cfg(test)]
 mod tests {
-    use crate::utils::{extract_filename_from_url, extract_pixiv_artwork_id};
+    use crate::utils::{
+        download_file_if_not_exists, extract_filename_from_url, extract_pixiv_artwork_id,
+        send_cloned, shrink_image, SizeLimitExceeded,
+    };
+    use std::io::Cursor;
     use std::str::FromStr;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Serves `body` with an optional ETag, dropping the first connection after `drop_after`
+    /// bytes of the body. Returns the lowercased requests received.
+    async fn serve(
+        body: Vec<u8>,
+        support_range: bool,
+        drop_after: usize,
+        etag: Option<&'static str>,
+    ) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/file.bin", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(vec![]));
+        let received = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = vec![];
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let n = socket.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let request = String::from_utf8_lossy(&request).to_lowercase();
+                let if_range = request
+                    .lines()
+                    .find_map(|line| line.strip_prefix("if-range: "));
+                let start = request
+                    .lines()
+                    .find_map(|line| line.strip_prefix("range: bytes="))
+                    .and_then(|range| range.trim_end_matches('-').parse::<usize>().ok())
+                    .filter(|_| support_range)
+                    .filter(|_| if_range.is_none() || if_range == etag);
+                let etag_header = etag
+                    .map(|etag| format!("ETag: {}\r\n", etag))
+                    .unwrap_or_default();
+                let header = match start {
+                    Some(start) => format!(
+                        "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\nContent-Range: bytes {}-{}/{}\r\n{}Connection: close\r\n\r\n",
+                        body.len() - start,
+                        start,
+                        body.len() - 1,
+                        body.len(),
+                        etag_header
+                    ),
+                    None => format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n",
+                        body.len(),
+                        etag_header
+                    ),
+                };
+                let start = start.unwrap_or(0);
+                let end = {
+                    let mut received = received.lock().unwrap();
+                    received.push(request.clone());
+                    if received.len() == 1 {
+                        drop_after
+                    } else {
+                        body.len()
+                    }
+                };
+                socket.write_all(header.as_bytes()).await.unwrap();
+                socket.write_all(&body[start..end]).await.unwrap();
+                socket.shutdown().await.unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    async fn download(
+        body: &[u8],
+        support_range: bool,
+        etag: Option<&'static str>,
+        name: &str,
+    ) -> Vec<String> {
+        let (url, requests) = serve(body.to_vec(), support_range, body.len() / 3, etag).await;
+        let path = std::env::temp_dir().join(format!("{}_{}", name, std::process::id()));
+        let path = path.to_str().unwrap();
+        let request = reqwest::Client::new().get(url);
+        let response = send_cloned(&request).await.unwrap();
+        let progress = Arc::new(Mutex::new((0, None)));
+        let last = progress.clone();
+        let on_progress = move |received, total| *last.lock().unwrap() = (received, total);
+        let size = download_file_if_not_exists(response, request, path, Some(&on_progress), None)
+            .await
+            .unwrap();
+        assert_eq!(size, body.len() as u64);
+        assert_eq!(
+            *progress.lock().unwrap(),
+            (body.len() as u64, Some(body.len() as u64))
+        );
+        assert_eq!(std::fs::read(path).unwrap(), body);
+        assert!(!std::path::Path::new(&format!("{}.part", path)).exists());
+        assert!(!std::path::Path::new(&format!("{}.part.validator", path)).exists());
+        std::fs::remove_file(path).unwrap();
+        let requests = requests.lock().unwrap().clone();
+        requests
+    }
+
+    #[tokio::test]
+    async fn resume_download_test() {
+        let body = (0..100_000).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        assert_eq!(
+            download(&body, true, None, "resume_download").await.len(),
+            2
+        );
+
+        let requests = download(&body, true, Some("\"v1\""), "resume_download_etag").await;
+        assert_eq!(requests.len(), 2);
+        assert!(requests[1].contains("if-range: \"v1\""));
+    }
+
+    #[tokio::test]
+    async fn changed_part_test() {
+        let body = (0..1000).map(|i| (i % 239) as u8).collect::<Vec<u8>>();
+        let (url, requests) = serve(body.clone(), true, body.len(), Some("\"v2\"")).await;
+        let path = std::env::temp_dir().join(format!("changed_part_{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        std::fs::write(format!("{}.part", path), [255; 100]).unwrap();
+        std::fs::write(format!("{}.part.validator", path), "\"v1\"").unwrap();
+
+        let request = reqwest::Client::new().get(url);
+        let response = send_cloned(&request).await.unwrap();
+        download_file_if_not_exists(response, request, path, None, None)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), body);
+        assert_eq!(requests.lock().unwrap().len(), 1);
+        assert!(!std::path::Path::new(&format!("{}.part.validator", path)).exists());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn oversized_download_test() {
+        let (url, _) = serve(vec![0; 1000], true, 1000, None).await;
+        let path = std::env::temp_dir().join(format!("oversized_download_{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let request = reqwest::Client::new().get(url);
+        let response = send_cloned(&request).await.unwrap();
+        let err = download_file_if_not_exists(response, request, path, None, Some(500))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<SizeLimitExceeded>(),
+            Some(&SizeLimitExceeded(1000, 500))
+        );
+        assert!(!std::path::Path::new(&format!("{}.part", path)).exists());
+        assert!(!std::path::Path::new(path).exists());
+    }
+
+    #[tokio::test]
+    async fn restart_download_test() {
+        let body = (0..100_000).map(|i| (i % 241) as u8).collect::<Vec<u8>>();
+        assert_eq!(
+            download(&body, false, None, "restart_download").await.len(),
+            2
+        );
+    }
 
     #[test]
     fn extract_filename_from_url_test_1() {
@@ -184,4 +625,24 @@ mod tests {
             None
         );
     }
+
+    #[test]
+    fn shrink_image_test() {
+        let noise = image::RgbImage::from_fn(512, 512, |x, y| {
+            image::Rgb([(x * 7 + y * 13) as u8, (x * y) as u8, (x ^ y) as u8])
+        });
+        let mut png = Cursor::new(vec![]);
+        image::DynamicImage::ImageRgb8(noise)
+            .write_to(&mut png, image::ImageOutputFormat::Png)
+            .unwrap();
+        let png = png.into_inner();
+
+        let max_bytes = png.len() as u64 / 10;
+        let shrunk = shrink_image(&png, max_bytes).unwrap();
+        assert!(shrunk.len() as u64 <= max_bytes);
+        assert_eq!(
+            image::guess_format(&shrunk).unwrap(),
+            image::ImageFormat::Jpeg
+        );
+    }
 }